@@ -0,0 +1,65 @@
+//! Measures per-token slicing cost as the input grows.
+//!
+//! Run with `cargo bench --bench slicing`. Each run lexes whitespace-separated words
+//! and slices every one with `str_from_mark`, so the time per token should stay flat
+//! as the input size grows rather than scaling with the cursor position.
+
+use rlex::{DefaultState, DefaultToken, Rlex};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+fn build_source(bytes: usize, word: &str) -> String {
+    let mut source = String::with_capacity(bytes + word.len() + 1);
+    while source.len() < bytes {
+        source.push_str(word);
+        source.push(' ');
+    }
+    source
+}
+
+fn lex_words(source: &str) -> (usize, Duration) {
+    let mut r: Rlex<DefaultState, DefaultToken> = Rlex::new(source, DefaultState::Default);
+    let mut tokens = 0;
+    let start = Instant::now();
+    while !r.at_end() {
+        r.mark();
+        r.next_until(' ');
+        black_box(r.str_from_mark());
+        tokens += 1;
+        r.next();
+    }
+    (tokens, start.elapsed())
+}
+
+fn slice_ranges(source: &str) -> (usize, Duration) {
+    let r: Rlex<DefaultState, DefaultToken> = Rlex::new(source, DefaultState::Default);
+    let len = source.chars().count();
+    let mut slices = 0;
+    let start = Instant::now();
+    let mut pos = 0;
+    while pos + 8 < len {
+        black_box(r.str_from_rng(pos, pos + 8));
+        black_box(r.span_of(pos, len));
+        slices += 1;
+        pos += 97;
+    }
+    (slices, start.elapsed())
+}
+
+fn report(name: &str, size: usize, (count, elapsed): (usize, Duration)) {
+    let per = elapsed.as_nanos() as f64 / count.max(1) as f64;
+    println!(
+        "{:<24} {:>9} bytes {:>9} ops {:>10.2?} total {:>8.1} ns/op",
+        name, size, count, elapsed, per
+    );
+}
+
+fn main() {
+    for size in [20_000, 200_000, 2_000_000] {
+        let ascii = build_source(size, "token");
+        report("ascii str_from_mark", size, lex_words(&ascii));
+        let utf8 = build_source(size, "tökén");
+        report("utf8 str_from_mark", size, lex_words(&utf8));
+        report("utf8 str_from_rng", size, slice_ranges(&utf8));
+    }
+}
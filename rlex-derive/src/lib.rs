@@ -0,0 +1,363 @@
+//! `#[derive(RlexToken)]` for [rlex](https://crates.io/crates/rlex).
+//!
+//! Enable the `derive` feature of `rlex` rather than depending on this crate directly.
+
+use proc_macro::{Delimiter, Group, TokenStream, TokenTree};
+
+/// Derives `rlex::RlexToken` for an enum, generating a lexer from attributes on its
+/// variants.
+///
+/// - `#[token("==")]` matches exact text.
+/// - `#[regex("[0-9]+")]` matches a pattern in the subset supported by `rlex::Regex`.
+/// - `#[skip]` on a variant drops its matches instead of emitting it.
+/// - `#[skip("\\s+")]` on the enum drops text matching a pattern without a variant.
+///
+/// At each position the longest match wins. Ties go to `#[token]` over `#[regex]`, and
+/// then to the variant declared first, so keywords beat identifiers of the same length.
+///
+/// Patterns are checked when the derive runs, so a malformed one is a compile error.
+///
+/// Unit variants are emitted as is. A variant with one field is built from the matched
+/// text with `From<&str>`, so the field can be a `&'a str` borrowed from the source or
+/// an owned `String`. The enum may have a single lifetime parameter for such fields.
+#[proc_macro_derive(RlexToken, attributes(token, regex, skip))]
+pub fn derive_rlex_token(input: TokenStream) -> TokenStream {
+    let generated = match TokenEnum::parse(input) {
+        Ok(token_enum) => token_enum.expand(),
+        Err(message) => format!("::core::compile_error!({:?});", message),
+    };
+    generated
+        .parse()
+        .expect("derive(RlexToken) generated invalid tokens")
+}
+
+enum Matcher {
+    Literal(String),
+    Regex(String),
+}
+
+struct Rule {
+    matcher: Matcher,
+    /// The expression building the variant from the matched `text`, if the rule has one.
+    value: Option<String>,
+    /// Whether the built variant is dropped rather than emitted.
+    skip: bool,
+}
+
+struct TokenEnum {
+    name: String,
+    lifetime: Option<String>,
+    rules: Vec<Rule>,
+}
+
+impl TokenEnum {
+    fn parse(input: TokenStream) -> Result<TokenEnum, String> {
+        let mut tokens = input.into_iter().peekable();
+        let mut skips = vec![];
+        loop {
+            match tokens.next() {
+                Some(TokenTree::Punct(p)) if p.as_char() == '#' => {
+                    if let Some(TokenTree::Group(group)) = tokens.next() {
+                        if let Some(Attr::Skip(Some(pattern))) = Attr::parse(&group)? {
+                            skips.push(pattern);
+                        }
+                    }
+                }
+                Some(TokenTree::Ident(ident)) if ident.to_string() == "enum" => break,
+                Some(TokenTree::Ident(ident)) if ident.to_string() == "struct" => {
+                    return Err("RlexToken can only be derived for enums".to_owned());
+                }
+                Some(_) => {}
+                None => return Err("RlexToken can only be derived for enums".to_owned()),
+            }
+        }
+        let name = match tokens.next() {
+            Some(TokenTree::Ident(ident)) => ident.to_string(),
+            _ => return Err("expected the enum's name".to_owned()),
+        };
+        let mut lifetime = None;
+        if matches!(tokens.peek(), Some(TokenTree::Punct(p)) if p.as_char() == '<') {
+            tokens.next();
+            let mut generics = vec![];
+            for token in tokens.by_ref() {
+                match token {
+                    TokenTree::Punct(p) if p.as_char() == '>' => break,
+                    token => generics.push(token.to_string()),
+                }
+            }
+            match generics.as_slice() {
+                [tick, name] if tick == "'" => lifetime = Some(format!("'{}", name)),
+                _ => {
+                    return Err(
+                        "RlexToken enums may only have a single lifetime parameter".to_owned()
+                    )
+                }
+            }
+        }
+        let body = tokens
+            .find_map(|token| match token {
+                TokenTree::Group(group) if group.delimiter() == Delimiter::Brace => Some(group),
+                _ => None,
+            })
+            .ok_or("expected the enum's variants")?;
+        let mut literals = vec![];
+        let mut regexes = vec![];
+        for variant in split_commas(body.stream()) {
+            parse_variant(&name, variant, &mut literals, &mut regexes)?;
+        }
+        let skips = skips.into_iter().map(|pattern| Rule {
+            matcher: Matcher::Regex(pattern),
+            value: None,
+            skip: true,
+        });
+        let mut rules = literals;
+        rules.extend(regexes);
+        rules.extend(skips);
+        Ok(TokenEnum {
+            name,
+            lifetime,
+            rules,
+        })
+    }
+
+    fn expand(&self) -> String {
+        let (impl_lifetime, ty) = match &self.lifetime {
+            Some(lifetime) => (lifetime.clone(), format!("{}<{}>", self.name, lifetime)),
+            None => ("'rlex".to_owned(), self.name.clone()),
+        };
+        let mut matchers = String::new();
+        let mut arms = String::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            let len = match &rule.matcher {
+                Matcher::Literal(lit) => {
+                    format!("rest.starts_with({lit:?}).then_some({lit:?}.len())")
+                }
+                Matcher::Regex(pattern) => format!(
+                    "{{
+                        static RE: ::std::sync::OnceLock<::rlex::Regex> =
+                            ::std::sync::OnceLock::new();
+                        RE.get_or_init(|| {{
+                            ::rlex::Regex::new({pattern:?})
+                                .expect(\"pattern checked by derive(RlexToken)\")
+                        }})
+                        .match_len(rest)
+                    }}"
+                ),
+            };
+            matchers += &format!(
+                "if let ::core::option::Option::Some(len) = {len} {{
+                    if len > 0 && best.is_none_or(|(best_len, _)| len > best_len) {{
+                        best = ::core::option::Option::Some((len, {index}usize));
+                    }}
+                }}
+                "
+            );
+            let value = match (&rule.value, rule.skip) {
+                (Some(value), false) => format!("::core::option::Option::Some({value})"),
+                (Some(value), true) => {
+                    format!("{{ let _ = {value}; ::core::option::Option::None }}")
+                }
+                (None, _) => "::core::option::Option::None".to_owned(),
+            };
+            arms += &format!("{index}usize => {value},\n");
+        }
+        format!(
+            "impl<{impl_lifetime}> ::rlex::RlexToken<{impl_lifetime}> for {ty} {{
+                fn lex_token<S: ::core::fmt::Debug>(
+                    r: &mut ::rlex::Rlex<{impl_lifetime}, S, Self>,
+                ) -> ::core::option::Option<Self> {{
+                    let rest: &{impl_lifetime} str = r.str_from_end();
+                    let mut best: ::core::option::Option<(usize, usize)> =
+                        ::core::option::Option::None;
+                    {matchers}
+                    let (len, rule) = best?;
+                    let text: &{impl_lifetime} str = &rest[..len];
+                    r.next_by(text.chars().count());
+                    match rule {{
+                        {arms}
+                        _ => ::core::option::Option::None,
+                    }}
+                }}
+            }}"
+        )
+    }
+}
+
+/// Reads a variant's attributes and shape, adding a rule for each `#[token]` and
+/// `#[regex]` on it.
+fn parse_variant(
+    enum_name: &str,
+    variant: Vec<TokenTree>,
+    literals: &mut Vec<Rule>,
+    regexes: &mut Vec<Rule>,
+) -> Result<(), String> {
+    let mut attrs = vec![];
+    let mut tokens = variant.into_iter();
+    let name = loop {
+        match tokens.next() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '#' => {
+                if let Some(TokenTree::Group(group)) = tokens.next() {
+                    if let Some(attr) = Attr::parse(&group)? {
+                        attrs.push(attr);
+                    }
+                }
+            }
+            Some(TokenTree::Ident(ident)) => break ident.to_string(),
+            Some(token) => return Err(format!("unexpected `{}` in enum body", token)),
+            None => return Ok(()),
+        }
+    };
+    let path = format!("{}::{}", enum_name, name);
+    let skip = attrs.iter().any(|attr| matches!(attr, Attr::Skip(None)));
+    let value = match tokens.next() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis => {
+            if split_commas(group.stream()).len() != 1 {
+                return Err(format!(
+                    "`{}` must have exactly one field to hold the matched text",
+                    path
+                ));
+            }
+            format!("{}(::core::convert::From::from(text))", path)
+        }
+        Some(TokenTree::Group(_)) => {
+            return Err(format!(
+                "`{}` must be a unit variant or have one unnamed field",
+                path
+            ));
+        }
+        _ => path,
+    };
+    for attr in attrs {
+        let value = Some(value.clone());
+        match attr {
+            Attr::Token(lit) => literals.push(Rule {
+                matcher: Matcher::Literal(lit),
+                value,
+                skip,
+            }),
+            Attr::Regex(pattern) => regexes.push(Rule {
+                matcher: Matcher::Regex(pattern),
+                value,
+                skip,
+            }),
+            Attr::Skip(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// An attribute this derive understands.
+enum Attr {
+    Token(String),
+    Regex(String),
+    Skip(Option<String>),
+}
+
+impl Attr {
+    /// Parses the bracketed part of `#[...]`, returning `None` for other attributes.
+    fn parse(group: &Group) -> Result<Option<Attr>, String> {
+        let mut tokens = group.stream().into_iter();
+        let name = match tokens.next() {
+            Some(TokenTree::Ident(ident)) => ident.to_string(),
+            _ => return Ok(None),
+        };
+        if !matches!(name.as_str(), "token" | "regex" | "skip") {
+            return Ok(None);
+        }
+        let arg = match tokens.next() {
+            Some(TokenTree::Group(args)) if args.delimiter() == Delimiter::Parenthesis => {
+                match args.stream().into_iter().next() {
+                    Some(TokenTree::Literal(lit)) => Some(string_literal(&lit.to_string())?),
+                    _ => return Err(format!("#[{}] expects a string literal", name)),
+                }
+            }
+            _ => None,
+        };
+        Ok(match (name.as_str(), arg) {
+            ("token", Some(lit)) if !lit.is_empty() => Some(Attr::Token(lit)),
+            ("token", _) => return Err("#[token] expects a non-empty string".to_owned()),
+            ("regex", Some(pattern)) => Some(Attr::Regex(checked(&name, pattern)?)),
+            ("regex", None) => return Err("#[regex] expects a pattern".to_owned()),
+            ("skip", Some(pattern)) => Some(Attr::Skip(Some(checked(&name, pattern)?))),
+            ("skip", None) => Some(Attr::Skip(None)),
+            _ => None,
+        })
+    }
+}
+
+/// Compiles `pattern` to reject a malformed one at compile time rather than when the
+/// lexer first runs.
+fn checked(attr: &str, pattern: String) -> Result<String, String> {
+    match rlex_regex::Regex::new(&pattern) {
+        Ok(_) => Ok(pattern),
+        Err(reason) => Err(format!("invalid regex in #[{}]: {}", attr, reason)),
+    }
+}
+
+/// Splits a token stream on its top-level commas, dropping empty pieces.
+fn split_commas(stream: TokenStream) -> Vec<Vec<TokenTree>> {
+    let mut pieces = vec![vec![]];
+    for token in stream {
+        match token {
+            TokenTree::Punct(p) if p.as_char() == ',' => pieces.push(vec![]),
+            token => pieces.last_mut().unwrap().push(token),
+        }
+    }
+    pieces.retain(|piece| !piece.is_empty());
+    pieces
+}
+
+/// Decodes the source text of a string literal, plain or raw.
+fn string_literal(source: &str) -> Result<String, String> {
+    if let Some(raw) = source.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let inner = &raw[hashes..raw.len() - hashes];
+        return inner
+            .strip_prefix('"')
+            .and_then(|inner| inner.strip_suffix('"'))
+            .map(str::to_owned)
+            .ok_or_else(|| format!("expected a string literal, found {}", source));
+    }
+    let inner = source
+        .strip_prefix('"')
+        .and_then(|inner| inner.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a string literal, found {}", source))?;
+    let mut out = String::new();
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('0') => out.push('\0'),
+            Some('\\') => out.push('\\'),
+            Some('\'') => out.push('\''),
+            Some('"') => out.push('"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let code = u8::from_str_radix(&hex, 16).map_err(|_| "invalid `\\x` escape")?;
+                out.push(code as char);
+            }
+            Some('u') => {
+                let hex: String = chars
+                    .by_ref()
+                    .skip(1)
+                    .take_while(|c| *c != '}')
+                    .filter(|c| *c != '_')
+                    .collect();
+                let c = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or("invalid `\\u` escape")?;
+                out.push(c);
+            }
+            Some('\n') => while chars.next_if(|c| c.is_whitespace()).is_some() {},
+            _ => return Err(format!("invalid escape in {}", source)),
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,313 @@
+//! The regular expression engine behind `rlex::Regex` and `#[regex]` in
+//! `#[derive(RlexToken)]`, shared so the derive can reject bad patterns at compile time.
+//!
+//! Use `rlex::Regex` rather than depending on this crate directly.
+
+use std::collections::BTreeSet;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A small regular expression, matched at the start of a string with longest-match
+/// semantics.
+///
+/// The supported syntax is literal chars, `.` (any char but `\n`), classes such as
+/// `[a-z_]` and `[^"]`, groups, alternation with `|`, and the greedy quantifiers `*`,
+/// `+`, `?`, `{n}`, `{n,}` and `{n,m}`. The escapes `\d`, `\w` and `\s` (and their
+/// negations) are ASCII-only, `\n`, `\r` and `\t` match those chars, and any other
+/// escaped char matches itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Regex {
+    pattern: String,
+    node: Node,
+}
+
+impl Regex {
+    /// Compiles a pattern.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message naming the pattern and the problem if it is malformed or uses
+    /// syntax outside the supported subset.
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser {
+            chars: pattern.chars().peekable(),
+        };
+        let node = parser
+            .alternation()
+            .and_then(|node| match parser.chars.next() {
+                None => Ok(node),
+                Some(c) => Err(format!("unexpected `{}`", c)),
+            })
+            .map_err(|reason| format!("`{}`: {}", pattern, reason))?;
+        Ok(Regex {
+            pattern: pattern.to_owned(),
+            node,
+        })
+    }
+
+    /// Returns the pattern the regex was compiled from.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns the byte length of the longest match at the start of `text`, or `None`
+    /// if the regex does not match there. A pattern that can match nothing may return
+    /// `Some(0)`.
+    pub fn match_len(&self, text: &str) -> Option<usize> {
+        let starts = BTreeSet::from([0]);
+        self.node.ends(text, &starts).last().copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+impl Node {
+    /// Returns every byte offset a match starting at one of `starts` can end at.
+    fn ends(&self, text: &str, starts: &BTreeSet<usize>) -> BTreeSet<usize> {
+        match self {
+            Node::Empty => starts.clone(),
+            Node::Char(expected) => step(text, starts, |c| c == *expected),
+            Node::Any => step(text, starts, |c| c != '\n'),
+            Node::Class(ranges, negated) => step(text, starts, |c| {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }),
+            Node::Concat(nodes) => {
+                let mut current = starts.clone();
+                for node in nodes {
+                    if current.is_empty() {
+                        break;
+                    }
+                    current = node.ends(text, &current);
+                }
+                current
+            }
+            Node::Alt(nodes) => nodes.iter().flat_map(|n| n.ends(text, starts)).collect(),
+            Node::Repeat(node, min, max) => {
+                let mut result = BTreeSet::new();
+                if *min == 0 {
+                    result.extend(starts.iter().copied());
+                }
+                let mut seen = result.clone();
+                let mut current = starts.clone();
+                let mut count = 0;
+                while max.is_none_or(|max| count < max) {
+                    let mut next = node.ends(text, &current);
+                    count += 1;
+                    if count >= *min {
+                        next.retain(|end| !seen.contains(end));
+                        seen.extend(next.iter().copied());
+                        result.extend(next.iter().copied());
+                    }
+                    if next.is_empty() {
+                        break;
+                    }
+                    current = next;
+                }
+                result
+            }
+        }
+    }
+}
+
+/// Returns the offsets just past a single char satisfying `pred` at each of `starts`.
+fn step(text: &str, starts: &BTreeSet<usize>, pred: impl Fn(char) -> bool) -> BTreeSet<usize> {
+    starts
+        .iter()
+        .filter_map(|&start| {
+            let c = text[start..].chars().next()?;
+            pred(c).then_some(start + c.len_utf8())
+        })
+        .collect()
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+struct Parser<'p> {
+    chars: Peekable<Chars<'p>>,
+}
+
+impl Parser<'_> {
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concatenation()?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            branches.push(self.concatenation()?);
+        }
+        Ok(match branches.len() {
+            1 => branches.remove(0),
+            _ => Node::Alt(branches),
+        })
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![];
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.remove(0),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn quantified(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.chars.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.chars.next();
+                    node = self.counted(node)?;
+                    continue;
+                }
+                _ => return Ok(node),
+            };
+            self.chars.next();
+            if self.chars.peek() == Some(&'?') {
+                return Err("lazy quantifiers are not supported".to_owned());
+            }
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    /// Parses the rest of a `{n}`, `{n,}` or `{n,m}` quantifier.
+    fn counted(&mut self, node: Node) -> Result<Node, String> {
+        let min = self.number()?;
+        let max = if self.chars.next_if_eq(&',').is_some() {
+            match self.chars.peek() {
+                Some('}') => None,
+                _ => Some(self.number()?),
+            }
+        } else {
+            Some(min)
+        };
+        if self.chars.next() != Some('}') {
+            return Err("unclosed `{`".to_owned());
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(format!("`{{{},{}}}` has max below min", min, max.unwrap()));
+        }
+        Ok(Node::Repeat(Box::new(node), min, max))
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        let mut digits = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+        digits
+            .parse()
+            .map_err(|_| "expected a number in `{}`".to_owned())
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.chars.next() {
+            Some('(') => {
+                let node = self.alternation()?;
+                if self.chars.next() != Some(')') {
+                    return Err("unclosed `(`".to_owned());
+                }
+                Ok(node)
+            }
+            Some('[') => self.class(),
+            Some('.') => Ok(Node::Any),
+            Some('\\') => self.escape(),
+            Some(c @ ('*' | '+' | '?' | '{')) => Err(format!("`{}` has nothing to repeat", c)),
+            Some(c @ ('^' | '$')) => Err(format!("anchor `{}` is not supported", c)),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err("unexpected end of pattern".to_owned()),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.chars.next().ok_or("trailing `\\`")?;
+        Ok(match c {
+            'd' => Node::Class(DIGIT.to_vec(), false),
+            'D' => Node::Class(DIGIT.to_vec(), true),
+            'w' => Node::Class(WORD.to_vec(), false),
+            'W' => Node::Class(WORD.to_vec(), true),
+            's' => Node::Class(SPACE.to_vec(), false),
+            'S' => Node::Class(SPACE.to_vec(), true),
+            c => Node::Char(unescape(c)),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut ranges = vec![];
+        let mut first = true;
+        loop {
+            let c = self.chars.next().ok_or("unclosed `[`")?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match c {
+                '\\' => {
+                    let c = self.chars.next().ok_or("unclosed `[`")?;
+                    match c {
+                        'd' => {
+                            ranges.extend_from_slice(DIGIT);
+                            continue;
+                        }
+                        'w' => {
+                            ranges.extend_from_slice(WORD);
+                            continue;
+                        }
+                        's' => {
+                            ranges.extend_from_slice(SPACE);
+                            continue;
+                        }
+                        'D' | 'W' | 'S' => {
+                            return Err(format!("`\\{}` is not supported in a class", c));
+                        }
+                        c => unescape(c),
+                    }
+                }
+                c => c,
+            };
+            let is_range = self.chars.peek() == Some(&'-')
+                && self.chars.clone().nth(1).is_some_and(|c| c != ']');
+            if !is_range {
+                ranges.push((lo, lo));
+                continue;
+            }
+            self.chars.next();
+            let hi = match self.chars.next().ok_or("unclosed `[`")? {
+                '\\' => unescape(self.chars.next().ok_or("unclosed `[`")?),
+                c => c,
+            };
+            if hi < lo {
+                return Err(format!("range `{}-{}` is out of order", lo, hi));
+            }
+            ranges.push((lo, hi));
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+}
+
+/// Maps the char after a `\` to the char it stands for.
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        c => c,
+    }
+}
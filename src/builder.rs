@@ -0,0 +1,249 @@
+use std::fmt;
+use std::fmt::Debug;
+use std::ops::RangeInclusive;
+
+use crate::{Diagnostic, Lexer, Rlex};
+
+/// What a rule in a [`LexerBuilder`] matches at the cursor.
+pub enum Pattern {
+    /// Exact text.
+    Literal(String),
+    /// A run of one or more chars that fall in any of the ranges.
+    Class(Vec<RangeInclusive<char>>),
+    /// A run of one or more chars that satisfy the predicate.
+    Predicate(Box<dyn Fn(char) -> bool>),
+    /// A custom matcher, given the rest of the source from the cursor and returning how
+    /// many bytes it matched.
+    #[allow(clippy::type_complexity)]
+    Closure(Box<dyn Fn(&str) -> Option<usize>>),
+}
+
+impl Pattern {
+    /// Matches exact text.
+    pub fn literal(lit: impl Into<String>) -> Pattern {
+        Pattern::Literal(lit.into())
+    }
+
+    /// Matches a run of one or more chars in any of `ranges`.
+    pub fn class(ranges: &[RangeInclusive<char>]) -> Pattern {
+        Pattern::Class(ranges.to_vec())
+    }
+
+    /// Matches a run of one or more chars satisfying `pred`.
+    pub fn predicate(pred: impl Fn(char) -> bool + 'static) -> Pattern {
+        Pattern::Predicate(Box::new(pred))
+    }
+
+    /// Matches with a custom function over the rest of the source, returning the number
+    /// of bytes matched.
+    pub fn closure(f: impl Fn(&str) -> Option<usize> + 'static) -> Pattern {
+        Pattern::Closure(Box::new(f))
+    }
+
+    /// Returns the number of bytes of `rest` this pattern matches, if any.
+    fn match_len(&self, rest: &str) -> Option<usize> {
+        let len = match self {
+            Pattern::Literal(lit) => rest.starts_with(lit.as_str()).then_some(lit.len())?,
+            Pattern::Class(ranges) => run_len(rest, |c| ranges.iter().any(|r| r.contains(&c))),
+            Pattern::Predicate(pred) => run_len(rest, pred),
+            Pattern::Closure(f) => f(rest)?.min(rest.len()),
+        };
+        (len > 0 && rest.is_char_boundary(len)).then_some(len)
+    }
+}
+
+impl Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Literal(lit) => f.debug_tuple("Literal").field(lit).finish(),
+            Pattern::Class(ranges) => f.debug_tuple("Class").field(ranges).finish(),
+            Pattern::Predicate(_) => write!(f, "Predicate(..)"),
+            Pattern::Closure(_) => write!(f, "Closure(..)"),
+        }
+    }
+}
+
+/// Returns the byte length of the run of chars at the start of `rest` satisfying `pred`.
+fn run_len(rest: &str, pred: impl Fn(char) -> bool) -> usize {
+    rest.char_indices()
+        .find(|(_, c)| !pred(*c))
+        .map_or(rest.len(), |(i, _)| i)
+}
+
+/// What a rule in a [`LexerBuilder`] does with the text it matched.
+pub enum Action<'a, S, T> {
+    /// Emits a clone of the token.
+    Emit(T),
+    /// Emits the token built from the matched text.
+    EmitWith(Box<dyn Fn(&'a str) -> T>),
+    /// Switches to another state.
+    Switch(S),
+    /// Enters another state with [`Rlex::state_push`], returning to the current one on
+    /// [`Action::Pop`].
+    Push(S),
+    /// Returns to the state saved by the last push with [`Rlex::state_pop`].
+    Pop,
+    /// Drops the matched text.
+    Skip,
+    /// Records an error spanning the matched text.
+    Error(String),
+    /// Runs several actions in order, such as emitting a token and then pushing a state.
+    Then(Vec<Action<'a, S, T>>),
+}
+
+impl<'a, S, T> Action<'a, S, T> {
+    /// Emits the token built from the matched text.
+    pub fn emit_with(f: impl Fn(&'a str) -> T + 'static) -> Action<'a, S, T> {
+        Action::EmitWith(Box::new(f))
+    }
+
+    /// Records an error with `message` spanning the matched text.
+    pub fn error(message: impl Into<String>) -> Action<'a, S, T> {
+        Action::Error(message.into())
+    }
+}
+
+impl<S: Debug, T: Debug> Debug for Action<'_, S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Emit(tok) => f.debug_tuple("Emit").field(tok).finish(),
+            Action::EmitWith(_) => write!(f, "EmitWith(..)"),
+            Action::Switch(state) => f.debug_tuple("Switch").field(state).finish(),
+            Action::Push(state) => f.debug_tuple("Push").field(state).finish(),
+            Action::Pop => write!(f, "Pop"),
+            Action::Skip => write!(f, "Skip"),
+            Action::Error(message) => f.debug_tuple("Error").field(message).finish(),
+            Action::Then(actions) => f.debug_tuple("Then").field(actions).finish(),
+        }
+    }
+}
+
+/// A pattern and the action to run when it wins.
+#[derive(Debug)]
+struct Rule<'a, S, T> {
+    pattern: Pattern,
+    action: Action<'a, S, T>,
+}
+
+/// Builds a [`RuleLexer`] from an ordered table of rules per state.
+///
+/// At each step the rules of the current state are tried at the cursor. The longest
+/// match wins, and among matches of the same length the rule added first wins.
+#[derive(Debug)]
+pub struct LexerBuilder<'a, S, T> {
+    states: Vec<(S, Vec<Rule<'a, S, T>>)>,
+}
+
+impl<'a, S, T> Default for LexerBuilder<'a, S, T> {
+    fn default() -> Self {
+        LexerBuilder { states: vec![] }
+    }
+}
+
+impl<'a, S, T> LexerBuilder<'a, S, T>
+where
+    S: PartialEq,
+{
+    /// Creates a builder with no rules.
+    pub fn new() -> LexerBuilder<'a, S, T> {
+        LexerBuilder::default()
+    }
+
+    /// Adds a rule to `state`, after the rules already added to it.
+    pub fn rule(mut self, state: S, pattern: Pattern, action: Action<'a, S, T>) -> Self {
+        let rule = Rule { pattern, action };
+        match self.states.iter_mut().find(|(s, _)| *s == state) {
+            Some((_, rules)) => rules.push(rule),
+            None => self.states.push((state, vec![rule])),
+        }
+        self
+    }
+
+    /// Compiles the rules into a lexer, ready for [`Rlex::lex`].
+    pub fn build(self) -> RuleLexer<'a, S, T> {
+        RuleLexer {
+            states: self.states,
+        }
+    }
+}
+
+/// A lexer compiled from a [`LexerBuilder`] rule table.
+///
+/// Text no rule matches produces no token, so [`Rlex::lex`] records an error and skips
+/// it.
+#[derive(Debug)]
+pub struct RuleLexer<'a, S, T> {
+    states: Vec<(S, Vec<Rule<'a, S, T>>)>,
+}
+
+impl<'a, S, T> Lexer<'a, S, T> for RuleLexer<'a, S, T>
+where
+    S: PartialEq + Clone + Debug,
+    T: Clone + Debug,
+{
+    fn lex_one(&mut self, r: &mut Rlex<'a, S, T>) -> Option<T> {
+        let rules = match self.states.iter().find(|(s, _)| *s == r.state) {
+            Some((_, rules)) => rules,
+            None => return None,
+        };
+        let source: &'a str = r.source;
+        let rest = &source[r.byte..];
+        let mut best: Option<(usize, &Rule<'a, S, T>)> = None;
+        for rule in rules {
+            if let Some(len) = rule.pattern.match_len(rest) {
+                if best.is_none_or(|(best_len, _)| len > best_len) {
+                    best = Some((len, rule));
+                }
+            }
+        }
+        let (len, rule) = best?;
+        let start = r.position;
+        let text = &rest[..len];
+        r.next_by(text.chars().count());
+        let mut tok = None;
+        run(&rule.action, r, start, text, &mut tok);
+        tok
+    }
+}
+
+/// Runs `action` for a match of `text` starting at `start`, storing any emitted token
+/// in `tok`.
+fn run<'a, S, T>(
+    action: &Action<'a, S, T>,
+    r: &mut Rlex<'a, S, T>,
+    start: usize,
+    text: &'a str,
+    tok: &mut Option<T>,
+) where
+    S: Clone + Debug,
+    T: Clone + Debug,
+{
+    match action {
+        Action::Emit(t) => {
+            *tok = Some(t.clone());
+            r.token_emitted();
+        }
+        Action::EmitWith(f) => {
+            *tok = Some(f(text));
+            r.token_emitted();
+        }
+        Action::Switch(state) => r.state_set(state.clone()),
+        Action::Push(state) => r.state_push(state.clone()),
+        Action::Pop => {
+            if r.state_pop().is_none() {
+                let span = r.span_of(start, r.position);
+                r.error_push(Diagnostic::error("no state to return to", span));
+            }
+        }
+        Action::Skip => {}
+        Action::Error(message) => {
+            let span = r.span_of(start, r.position);
+            r.error_push(Diagnostic::error(message.clone(), span));
+        }
+        Action::Then(actions) => {
+            for action in actions {
+                run(action, r, start, text, tok);
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{Cursor, Rlex, TraceCategory};
+
+/// An opaque snapshot of lexer state, taken by [`Rlex::checkpoint`] and restored by
+/// [`Rlex::rollback`] or released by [`Rlex::commit`].
+#[derive(Debug, Clone)]
+pub struct Checkpoint<S> {
+    /// How many checkpoints were live when this one was taken.
+    depth: usize,
+    cursor: Cursor,
+    marked_position: usize,
+    state: S,
+    state_stack: Vec<S>,
+    collection: Vec<char>,
+    token_count: usize,
+    popped_count: usize,
+    tokens_since_transition: usize,
+    error_count: usize,
+    trace_len: usize,
+}
+
+/// The outcome of a speculative closure passed to [`Rlex::try_lex`].
+pub trait Attempt {
+    /// Returns `true` if the attempt succeeded and its effects should be kept.
+    fn is_success(&self) -> bool;
+}
+
+impl<T> Attempt for Option<T> {
+    fn is_success(&self) -> bool {
+        self.is_some()
+    }
+}
+
+impl<T, E> Attempt for Result<T, E> {
+    fn is_success(&self) -> bool {
+        self.is_ok()
+    }
+}
+
+impl Attempt for bool {
+    fn is_success(&self) -> bool {
+        *self
+    }
+}
+
+impl<'a, S, T> Rlex<'a, S, T>
+where
+    T: std::fmt::Debug + Clone,
+    S: std::fmt::Debug + Clone,
+{
+    /// Snapshots the position, mark, state and state stack, collection, tokens, error
+    /// count, trace length and the token count the next change of state will see.
+    ///
+    /// While a checkpoint is live, popped tokens are copied so a rollback can put them
+    /// back. Release it with [`Rlex::commit`] once it is no longer needed, as
+    /// [`Rlex::try_lex`] does, to stop paying for those copies.
+    pub fn checkpoint(&mut self) -> Checkpoint<S> {
+        let before = self.trace_enter(TraceCategory::State);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "checkpoint", vec![], None);
+        }
+        let depth = self.checkpoint_depth;
+        self.checkpoint_depth += 1;
+        self.token_clone = Some(T::clone);
+        Checkpoint {
+            depth,
+            cursor: self.cursor(),
+            marked_position: self.marked_position,
+            state: self.state.clone(),
+            state_stack: self.state_stack.clone(),
+            collection: self.collection.clone(),
+            token_count: self.tokens.len(),
+            popped_count: self.popped_tokens.len(),
+            tokens_since_transition: self.state_hooks.tokens_since,
+            error_count: self.errors.len(),
+            trace_len: self.trace_count,
+        }
+    }
+
+    /// Restores the lexer to a checkpoint. Tokens and errors pushed since are dropped,
+    /// tokens popped since are put back, and the trace is cut back to where it stood.
+    ///
+    /// The checkpoint stays live, so it can be rolled back to again, while checkpoints
+    /// taken after it are released.
+    pub fn rollback(&mut self, checkpoint: &Checkpoint<S>) {
+        let before = self.trace_enter(TraceCategory::State);
+        self.restore(checkpoint.cursor);
+        self.marked_position = checkpoint.marked_position;
+        self.state = checkpoint.state.clone();
+        self.state_stack = checkpoint.state_stack.clone();
+        self.collection = checkpoint.collection.clone();
+        while self.popped_tokens.len() > checkpoint.popped_count {
+            let (index, tok, span) = self.popped_tokens.pop().unwrap();
+            self.tokens.truncate(index);
+            self.token_spans.truncate(index);
+            self.tokens.push(tok);
+            self.token_spans.push(span);
+        }
+        self.tokens.truncate(checkpoint.token_count);
+        self.token_spans.truncate(checkpoint.token_count);
+        self.state_hooks.tokens_since = checkpoint.tokens_since_transition;
+        self.errors.truncate(checkpoint.error_count);
+        self.trace_sink.truncate(checkpoint.trace_len);
+        self.trace_count = checkpoint.trace_len;
+        self.checkpoint_depth = checkpoint.depth + 1;
+        if self.trace_leave(&before) {
+            self.trace_log(before, "rollback", vec![], None);
+        }
+    }
+
+    /// Keeps everything done since a checkpoint and releases it, along with any
+    /// checkpoints taken after it. Once no checkpoint is live, popped tokens are no
+    /// longer copied.
+    pub fn commit(&mut self, checkpoint: &Checkpoint<S>) {
+        let before = self.trace_enter(TraceCategory::State);
+        self.checkpoint_depth = checkpoint.depth;
+        if self.checkpoint_depth == 0 {
+            self.token_clone = None;
+            self.popped_tokens.clear();
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "commit", vec![], None);
+        }
+    }
+
+    /// Runs `f` speculatively, rolling the lexer back if it returns `None`, `Err` or
+    /// `false` and committing otherwise.
+    pub fn try_lex<R: Attempt>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let checkpoint = self.checkpoint();
+        let result = f(self);
+        if !result.is_success() {
+            self.rollback(&checkpoint);
+        }
+        self.commit(&checkpoint);
+        result
+    }
+}
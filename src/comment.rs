@@ -0,0 +1,63 @@
+/// Describes line and block comment delimiters.
+///
+/// The default config has no comments, so nothing is treated as a comment until one
+/// is set with [`Rlex::comment_config_set`](crate::Rlex::comment_config_set).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommentConfig {
+    /// Delimiters that start a comment running to the end of the line, such as `//`.
+    pub line: Vec<String>,
+    /// Opening and closing delimiters of block comments, such as `("/*", "*/")`.
+    pub block: Vec<(String, String)>,
+    /// Whether block comments nest, as they do in Rust.
+    pub nested: bool,
+}
+
+impl CommentConfig {
+    /// Creates a config with no comments.
+    pub fn new() -> CommentConfig {
+        CommentConfig::default()
+    }
+
+    /// C-style `//` line comments and `/* */` block comments.
+    pub fn c() -> CommentConfig {
+        CommentConfig::new().with_line("//").with_block("/*", "*/")
+    }
+
+    /// Rust comments, where block comments nest.
+    pub fn rust() -> CommentConfig {
+        CommentConfig::c().with_nesting(true)
+    }
+
+    /// Shell-style `#` line comments.
+    pub fn shell() -> CommentConfig {
+        CommentConfig::new().with_line("#")
+    }
+
+    /// SQL `--` line comments and `/* */` block comments.
+    pub fn sql() -> CommentConfig {
+        CommentConfig::new().with_line("--").with_block("/*", "*/")
+    }
+
+    /// HTML `<!-- -->` comments.
+    pub fn html() -> CommentConfig {
+        CommentConfig::new().with_block("<!--", "-->")
+    }
+
+    /// Adds a line comment delimiter.
+    pub fn with_line(mut self, start: &str) -> CommentConfig {
+        self.line.push(start.to_owned());
+        self
+    }
+
+    /// Adds a block comment delimiter pair.
+    pub fn with_block(mut self, open: &str, close: &str) -> CommentConfig {
+        self.block.push((open.to_owned(), close.to_owned()));
+        self
+    }
+
+    /// Sets whether block comments nest.
+    pub fn with_nesting(mut self, nested: bool) -> CommentConfig {
+        self.nested = nested;
+        self
+    }
+}
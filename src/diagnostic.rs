@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::snippet::{line_at, lines_in, SourceLine};
+use crate::Span;
+
+/// Lines of a multi-line span shown before and after the elided middle.
+const CONTEXT_LINES: usize = 2;
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+    Help,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+            Severity::Help => write!(f, "help"),
+        }
+    }
+}
+
+/// A secondary span pointed at by a [`Diagnostic`], with a message explaining it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A problem found in the source, rendered as a rustc-style snippet by
+/// [`Diagnostic::render`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// The span the diagnostic is about, underlined with `^`.
+    pub span: Span,
+    /// Related spans, underlined with `-` and followed by their message.
+    pub labels: Vec<Label>,
+    /// Extra lines printed after the snippet.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Creates a diagnostic about `span` with no labels or notes.
+    pub fn new(severity: Severity, message: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            span,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    /// Creates an error about `span`.
+    pub fn error(message: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message, span)
+    }
+
+    /// Creates a warning about `span`.
+    pub fn warning(message: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message, span)
+    }
+
+    /// Adds a secondary label pointing at `span`.
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    /// Adds a note printed after the snippet.
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic against the source its spans point into, in the style of
+    /// rustc:
+    ///
+    /// ```text
+    /// error: unterminated string
+    ///  --> 1:9
+    ///   |
+    /// 1 | let s = "abc
+    ///   |         ^^^^^
+    ///   |
+    ///   = note: strings must be closed on the line they start
+    /// ```
+    ///
+    /// Spans covering several lines underline each line, with long spans eliding their
+    /// middle lines.
+    pub fn render(&self, source: &str) -> String {
+        let mut annotations = vec![Annotation::new(source, self.span, '^', None)];
+        for label in &self.labels {
+            annotations.push(Annotation::new(
+                source,
+                label.span,
+                '-',
+                Some(&label.message),
+            ));
+        }
+        let mut shown: BTreeMap<usize, SourceLine<'_>> = BTreeMap::new();
+        for annotation in &annotations {
+            for line in annotation.shown_lines() {
+                shown.insert(line.number, *line);
+            }
+        }
+        let last = shown.keys().next_back().copied().unwrap_or(1);
+        let gutter = last.to_string().len();
+        let start = line_at(source, self.span.start);
+        let mut out = format!("{}\n", self);
+        out += &format!(
+            "{:gutter$}--> {}:{}\n",
+            "",
+            start.number,
+            self.span.start - start.start + 1
+        );
+        out += &format!("{:gutter$} |\n", "");
+        let mut prev: Option<usize> = None;
+        for line in shown.values() {
+            if prev.is_some_and(|prev| line.number > prev + 1) {
+                out += "...\n";
+            }
+            prev = Some(line.number);
+            out += format!("{:>gutter$} | {}", line.number, line.display()).trim_end();
+            out += "\n";
+            for annotation in &annotations {
+                if let Some(underline) = annotation.underline(line) {
+                    out += &format!("{:gutter$} | {}\n", "", underline);
+                }
+            }
+        }
+        if !self.notes.is_empty() {
+            out += &format!("{:gutter$} |\n", "");
+            for note in &self.notes {
+                out += &format!("{:gutter$} = note: {}\n", "", note);
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    /// Renders the headline, `severity: message`, without a snippet.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// A span to underline in a rendered snippet, with the lines it covers.
+struct Annotation<'s, 'm> {
+    start: usize,
+    end: usize,
+    marker: char,
+    message: Option<&'m str>,
+    lines: Vec<SourceLine<'s>>,
+}
+
+impl<'s, 'm> Annotation<'s, 'm> {
+    fn new(source: &'s str, span: Span, marker: char, message: Option<&'m str>) -> Self {
+        Annotation {
+            start: span.start,
+            end: span.end.max(span.start + 1),
+            marker,
+            message,
+            lines: lines_in(source, span.start, span.end),
+        }
+    }
+
+    /// Returns the lines to print, dropping the middle of long spans.
+    fn shown_lines(&self) -> Vec<&SourceLine<'s>> {
+        if self.lines.len() <= CONTEXT_LINES * 2 + 1 {
+            return self.lines.iter().collect();
+        }
+        let head = self.lines.iter().take(CONTEXT_LINES);
+        let tail = self.lines.iter().skip(self.lines.len() - CONTEXT_LINES);
+        head.chain(tail).collect()
+    }
+
+    /// Returns the underline for `line`, with the message on the span's last line, or
+    /// `None` if the span does not touch it. The line break, however long, is marked as
+    /// one column past the text, as is the end of the source.
+    fn underline(&self, line: &SourceLine<'_>) -> Option<String> {
+        let line_end = line.start + line.len() + line.break_len.max(1);
+        if self.start >= line_end || self.end <= line.start {
+            return None;
+        }
+        let from = (self.start.max(line.start) - line.start).min(line.len());
+        let to = (self.end.min(line_end) - line.start).min(line.len() + 1);
+        let marks: String = std::iter::repeat_n(self.marker, (to - from).max(1)).collect();
+        let mut underline = format!("{:from$}{}", "", marks);
+        let is_last = self.lines.last().map(|l| l.number) == Some(line.number);
+        if let (true, Some(message)) = (is_last, self.message) {
+            underline += " ";
+            underline += message;
+        }
+        Some(underline)
+    }
+}
@@ -0,0 +1,26 @@
+use std::fmt;
+
+/// Errors produced while constructing or driving an [`Rlex`](crate::Rlex).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RlexError {
+    /// The source string was empty where input was required.
+    EmptySource,
+    /// No mark has been recorded under the given name.
+    UnknownMark(String),
+    /// A regex pattern was malformed or used unsupported syntax.
+    #[cfg(feature = "derive")]
+    InvalidRegex(String),
+}
+
+impl fmt::Display for RlexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RlexError::EmptySource => write!(f, "source string is empty"),
+            RlexError::UnknownMark(name) => write!(f, "no mark named `{}`", name),
+            #[cfg(feature = "derive")]
+            RlexError::InvalidRegex(reason) => write!(f, "invalid regex {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RlexError {}
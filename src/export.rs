@@ -0,0 +1,135 @@
+use std::io::Write;
+use std::time::Duration;
+
+use crate::{TraceCategory, TraceEvent, TraceSink};
+
+impl TraceEvent {
+    /// Renders the event as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let args: Vec<String> = self.args.iter().map(|arg| json_str(arg)).collect();
+        format!(
+            "{{\"index\":{},\"method\":{},\"category\":{},\"args\":[{}],\"result\":{},\"pos_before\":{},\"pos_after\":{},\"mark\":{},\"state\":{},\"last_token\":{},\"depth\":{},\"time_us\":{},\"duration_us\":{}}}",
+            self.index,
+            json_str(self.method),
+            json_str(category_name(self.category)),
+            args.join(","),
+            json_opt(&self.result),
+            self.pos_before,
+            self.pos_after,
+            self.mark,
+            json_str(&self.state),
+            json_opt(&self.last_token),
+            self.depth,
+            micros(self.time),
+            micros(self.duration),
+        )
+    }
+
+    /// Renders the event as a complete (`"ph":"X"`) event in the Chrome trace event
+    /// format.
+    pub fn to_chrome_json(&self) -> String {
+        let args: Vec<String> = self.args.iter().map(|arg| json_str(arg)).collect();
+        format!(
+            "{{\"name\":{},\"cat\":{},\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":1,\"args\":{{\"index\":{},\"args\":[{}],\"result\":{},\"pos_before\":{},\"pos_after\":{},\"mark\":{},\"state\":{},\"last_token\":{}}}}}",
+            json_str(self.method),
+            json_str(category_name(self.category)),
+            micros(self.time),
+            micros(self.duration),
+            self.index,
+            args.join(","),
+            json_opt(&self.result),
+            self.pos_before,
+            self.pos_after,
+            self.mark,
+            json_str(&self.state),
+            json_opt(&self.last_token),
+        )
+    }
+}
+
+/// Renders `events` as JSON Lines, one object per event.
+pub fn to_json_lines<'e>(events: impl IntoIterator<Item = &'e TraceEvent>) -> String {
+    let mut out = "".to_owned();
+    for event in events {
+        out += &event.to_json();
+        out += "\n";
+    }
+    out
+}
+
+/// Renders `events` as a Chrome trace, loadable in `chrome://tracing` and Perfetto.
+pub fn to_chrome_trace<'e>(events: impl IntoIterator<Item = &'e TraceEvent>) -> String {
+    let events: Vec<String> = events.into_iter().map(|e| e.to_chrome_json()).collect();
+    format!(
+        "{{\"traceEvents\":[{}],\"displayTimeUnit\":\"ns\"}}",
+        events.join(",")
+    )
+}
+
+/// Streams each event as a line of JSON to a writer, keeping nothing in memory.
+///
+/// Write errors are ignored so tracing never interrupts lexing.
+#[derive(Debug)]
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    /// Creates a sink that writes to `writer`.
+    pub fn new(writer: W) -> JsonLinesSink<W> {
+        JsonLinesSink { writer }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> TraceSink for JsonLinesSink<W> {
+    fn record(&mut self, event: TraceEvent) {
+        let _ = writeln!(self.writer, "{}", event.to_json());
+    }
+}
+
+fn category_name(category: TraceCategory) -> &'static str {
+    match category {
+        TraceCategory::Navigation => "navigation",
+        TraceCategory::Peeks => "peeks",
+        TraceCategory::Queries => "queries",
+        TraceCategory::Tokens => "tokens",
+        TraceCategory::State => "state",
+        TraceCategory::Collection => "collection",
+    }
+}
+
+/// Formats a duration as microseconds with nanosecond precision, as Chrome expects.
+fn micros(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    format!("{}.{:03}", nanos / 1000, nanos % 1000)
+}
+
+fn json_opt(value: &Option<String>) -> String {
+    match value {
+        Some(value) => json_str(value),
+        None => "null".to_owned(),
+    }
+}
+
+fn json_str(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use crate::Rlex;
+
+type Matcher<S> = Box<dyn Fn(&S) -> bool>;
+type Hook<S> = Box<dyn FnMut(&Transition<'_, S>)>;
+type Validator<S> = Box<dyn FnMut(&Transition<'_, S>) -> Result<(), String>>;
+
+/// A change of state, handed to the transition validator and to `on_enter`/`on_exit`
+/// hooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition<'s, S> {
+    /// The state being left.
+    pub from: &'s S,
+    /// The state being entered.
+    pub to: &'s S,
+    /// The cursor position when the change happened.
+    pub position: usize,
+    /// How many tokens were pushed or emitted by a [`Lexer`](crate::Lexer) since the
+    /// previous change of state.
+    pub tokens_since: usize,
+}
+
+/// The hooks and validator registered on a lexer.
+///
+/// Hooks are `'static` so they never shorten the life of slices borrowed from the
+/// source. Share state with them through an `Rc<RefCell<_>>` or similar.
+pub(crate) struct StateHooks<S> {
+    enter: Vec<(Matcher<S>, Hook<S>)>,
+    exit: Vec<(Matcher<S>, Hook<S>)>,
+    validator: Option<Validator<S>>,
+    /// Tokens pushed or emitted since the last change of state.
+    pub(crate) tokens_since: usize,
+    /// Whether the current [`Lexer::lex_one`](crate::Lexer::lex_one) call has already
+    /// counted the token it returns.
+    pub(crate) emitted: bool,
+}
+
+impl<S> Default for StateHooks<S> {
+    fn default() -> Self {
+        StateHooks {
+            enter: vec![],
+            exit: vec![],
+            validator: None,
+            tokens_since: 0,
+            emitted: false,
+        }
+    }
+}
+
+impl<S> fmt::Debug for StateHooks<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateHooks")
+            .field("enter", &self.enter.len())
+            .field("exit", &self.exit.len())
+            .field("validator", &self.validator.is_some())
+            .finish()
+    }
+}
+
+impl<'a, S, T> Rlex<'a, S, T>
+where
+    T: std::fmt::Debug,
+    S: std::fmt::Debug + PartialEq + 'static,
+{
+    /// Registers `hook` to run whenever the lexer enters `state`, after the change.
+    pub fn on_enter(&mut self, state: S, hook: impl FnMut(&Transition<'_, S>) + 'static) {
+        let matcher: Matcher<S> = Box::new(move |s| *s == state);
+        self.state_hooks.enter.push((matcher, Box::new(hook)));
+    }
+
+    /// Registers `hook` to run whenever the lexer leaves `state`, before the change.
+    pub fn on_exit(&mut self, state: S, hook: impl FnMut(&Transition<'_, S>) + 'static) {
+        let matcher: Matcher<S> = Box::new(move |s| *s == state);
+        self.state_hooks.exit.push((matcher, Box::new(hook)));
+    }
+}
+
+impl<'a, S, T> Rlex<'a, S, T>
+where
+    T: std::fmt::Debug,
+    S: std::fmt::Debug,
+{
+    /// Sets the validator consulted before every change of state. Returning `Err`
+    /// rejects the change: the state stays as it was and the message is recorded as an
+    /// error at the cursor.
+    pub fn state_validator_set(
+        &mut self,
+        validator: impl FnMut(&Transition<'_, S>) -> Result<(), String> + 'static,
+    ) {
+        self.state_hooks.validator = Some(Box::new(validator));
+    }
+
+    /// Checks a change of state to `to` with the validator and, if it is allowed, runs
+    /// the exit hooks of the current state. Returns whether the change may go ahead.
+    pub(crate) fn transition_begin(&mut self, to: &S) -> bool {
+        let transition = Transition {
+            from: &self.state,
+            to,
+            position: self.position,
+            tokens_since: self.state_hooks.tokens_since,
+        };
+        if let Some(validator) = &mut self.state_hooks.validator {
+            if let Err(message) = validator(&transition) {
+                let diagnostic = self.error_here(message);
+                self.error_push(diagnostic);
+                return false;
+            }
+        }
+        for (matches, hook) in &mut self.state_hooks.exit {
+            if matches(transition.from) {
+                hook(&transition);
+            }
+        }
+        true
+    }
+
+    /// Runs the enter hooks of the new state after a change from `from`.
+    pub(crate) fn transition_end(&mut self, from: &S) {
+        let transition = Transition {
+            from,
+            to: &self.state,
+            position: self.position,
+            tokens_since: self.state_hooks.tokens_since,
+        };
+        for (matches, hook) in &mut self.state_hooks.enter {
+            if matches(transition.to) {
+                hook(&transition);
+            }
+        }
+        self.state_hooks.tokens_since = 0;
+    }
+
+    /// Counts a token toward [`Transition::tokens_since`]. Lexers that emit a token
+    /// before changing state in the same call report it here, so the change sees it.
+    pub(crate) fn token_emitted(&mut self) {
+        self.state_hooks.tokens_since += 1;
+        self.state_hooks.emitted = true;
+    }
+}
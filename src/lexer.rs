@@ -0,0 +1,108 @@
+use std::fmt::Debug;
+use std::iter::FusedIterator;
+
+use crate::{Rlex, Spanned};
+
+/// Produces tokens one at a time from an [`Rlex`], for driving with [`Rlex::lex`].
+///
+/// Each call starts with the cursor on the first char of the next token and should
+/// leave it on the first char after the token, so the token spans `start..pos()`.
+/// Returning `None` means the consumed text produced no token, as with whitespace or
+/// comments.
+///
+/// The lexer's source lifetime `'a` is part of the trait, so tokens can hold `&'a str`
+/// slices of the source. Any `FnMut(&mut Rlex<'a, S, T>) -> Option<T>` closure is a
+/// `Lexer`.
+pub trait Lexer<'a, S, T> {
+    /// Lexes the next token from the cursor.
+    fn lex_one(&mut self, r: &mut Rlex<'a, S, T>) -> Option<T>;
+}
+
+impl<'a, S, T, F> Lexer<'a, S, T> for F
+where
+    F: FnMut(&mut Rlex<'a, S, T>) -> Option<T>,
+{
+    fn lex_one(&mut self, r: &mut Rlex<'a, S, T>) -> Option<T> {
+        self(r)
+    }
+}
+
+/// An iterator of spanned tokens, lexed lazily by a [`Lexer`] as it is advanced.
+///
+/// Returned by [`Rlex::lex`]. The lexer stays borrowed while the stream is alive, so
+/// errors recorded along the way can be read from it afterwards.
+#[derive(Debug)]
+pub struct TokenStream<'r, 'a, L, S, T> {
+    rlex: &'r mut Rlex<'a, S, T>,
+    lexer: L,
+    /// Where the last token consuming nothing was yielded, so a second one there can be
+    /// caught before the stream loops forever.
+    zero_width_at: Option<usize>,
+}
+
+impl<'r, 'a, L, S, T> TokenStream<'r, 'a, L, S, T> {
+    pub(crate) fn new(rlex: &'r mut Rlex<'a, S, T>, lexer: L) -> Self {
+        TokenStream {
+            rlex,
+            lexer,
+            zero_width_at: None,
+        }
+    }
+
+    /// Returns the lexer being driven.
+    pub fn rlex(&mut self) -> &mut Rlex<'a, S, T> {
+        self.rlex
+    }
+}
+
+impl<'a, L, S, T> Iterator for TokenStream<'_, 'a, L, S, T>
+where
+    L: Lexer<'a, S, T>,
+    S: Debug,
+    T: Debug,
+{
+    type Item = Spanned<T>;
+
+    /// Calls [`Lexer::lex_one`] until it produces a token or the input runs out.
+    ///
+    /// A token that consumes nothing is yielded with an empty span, once per position.
+    /// Any other call that consumes nothing, including a second zero-width token at the
+    /// same position, is dropped: the driver records an "unexpected character" error and
+    /// skips the current char so lexing always makes progress.
+    fn next(&mut self) -> Option<Spanned<T>> {
+        while !self.rlex.at_end() {
+            let start = self.rlex.pos();
+            self.rlex.state_hooks.emitted = false;
+            let tok = self.lexer.lex_one(self.rlex);
+            if tok.is_some() && !self.rlex.state_hooks.emitted {
+                self.rlex.state_hooks.tokens_since += 1;
+            }
+            let end = self.rlex.pos();
+            let span = self.rlex.span_of(start, end);
+            if end > start {
+                self.zero_width_at = None;
+            } else if tok.is_some() && self.zero_width_at != Some(start) {
+                self.zero_width_at = Some(start);
+            } else {
+                self.rlex.goto_pos(start);
+                let diagnostic = self.rlex.error_here("unexpected character");
+                self.rlex.error_push(diagnostic);
+                self.rlex.next();
+                self.zero_width_at = None;
+                continue;
+            }
+            if let Some(tok) = tok {
+                return Some(Spanned::new(tok, span));
+            }
+        }
+        None
+    }
+}
+
+impl<'a, L, S, T> FusedIterator for TokenStream<'_, 'a, L, S, T>
+where
+    L: Lexer<'a, S, T>,
+    S: Debug,
+    T: Debug,
+{
+}
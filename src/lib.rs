@@ -1,495 +1,1518 @@
+#[cfg(all(test, feature = "derive"))]
+extern crate self as rlex;
+
+mod builder;
+mod checkpoint;
+mod comment;
+mod diagnostic;
+mod error;
+#[cfg(feature = "trace-export")]
+mod export;
+mod hooks;
+mod lexer;
+mod quote;
+#[cfg(feature = "derive")]
+mod regex;
+mod scan;
+mod snippet;
+mod span;
+#[cfg(feature = "derive")]
+mod token;
+mod trace;
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+pub use builder::{Action, LexerBuilder, Pattern, RuleLexer};
+pub use checkpoint::{Attempt, Checkpoint};
+pub use comment::CommentConfig;
+pub use diagnostic::{Diagnostic, Label, Severity};
+pub use error::RlexError;
+#[cfg(feature = "trace-export")]
+pub use export::{to_chrome_trace, to_json_lines, JsonLinesSink};
+use hooks::StateHooks;
+pub use hooks::Transition;
+pub use lexer::{Lexer, TokenStream};
+pub use quote::QuoteConfig;
+#[cfg(feature = "derive")]
+pub use regex::Regex;
+#[cfg(feature = "derive")]
+pub use rlex_derive::RlexToken;
+use scan::{ScanRules, Scanner};
+use snippet::LineIndex;
+pub use span::{Span, Spanned};
+#[cfg(feature = "derive")]
+pub use token::RlexToken;
+use trace::TraceFrame;
+pub use trace::{
+    CallbackSink, MemorySink, NoopSink, RingSink, TraceCategory, TraceEvent, TraceFilter,
+    TraceReplay, TraceSink, WriterSink,
+};
+
 /// A generic lexer that allows traversal, peeking, marking, and collection of characters
 /// from a string source. Useful for building parsers or tokenizers.
+///
+/// The lexer borrows its source and walks it by byte offset, so slices returned by the
+/// `str_from_*` methods live as long as the source rather than the lexer.
+///
+/// The cursor ranges over `0..=len`, where `len` is the number of chars in the source.
+/// Sitting at `len` means the input is fully consumed, which is also where an empty
+/// source starts.
 #[derive(Debug)]
-pub struct Rlex<S, T> {
-    source: String,
-    chars: Vec<char>,
+pub struct Rlex<'a, S, T> {
+    source: &'a str,
+    char_count: usize,
+    byte_index: Vec<usize>,
     position: usize,
-    max_position: usize,
+    byte: usize,
+    line: usize,
+    line_start: usize,
     marked_position: usize,
+    mark_stack: Vec<Cursor>,
+    named_marks: HashMap<String, Cursor>,
     state: S,
+    state_stack: Vec<S>,
+    state_hooks: StateHooks<S>,
+    quote_config: QuoteConfig,
+    comment_config: CommentConfig,
+    scanner: Scanner,
     collection: Vec<char>,
     collection_str: String,
     tokens: Vec<T>,
+    token_spans: Vec<Span>,
+    popped_tokens: Vec<(usize, T, Span)>,
+    token_clone: Option<fn(&T) -> T>,
+    checkpoint_depth: usize,
+    errors: Vec<Diagnostic>,
     should_trace: bool,
-    trace: Vec<String>,
+    trace_sink: Box<dyn TraceSink>,
+    trace_count: usize,
+    trace_depth: usize,
+    trace_filter: TraceFilter,
+    trace_epoch: Instant,
 }
 
-impl<S, T> Rlex<S, T>
+impl<'a, S, T> Rlex<'a, S, T>
 where
     T: std::fmt::Debug,
     S: std::fmt::Debug,
 {
-    /// Creates a new lexer from a non-empty string and an initial state.
-    ///
-    /// # Errors
+    /// Creates a new lexer from a string and an initial state.
     ///
-    /// Returns an error if the source string is empty.
-    pub fn new(source: &str, state: S) -> Rlex<S, T> {
-        let chars: Vec<char> = source.chars().collect();
-        let length = chars.len();
-        let rlex = Rlex {
-            source: source.to_owned(),
-            chars,
+    /// An empty source is valid; the lexer simply starts at the end of input.
+    pub fn new(source: &'a str, state: S) -> Rlex<'a, S, T> {
+        let char_count = source.chars().count();
+        Rlex {
+            source,
+            char_count,
+            byte_index: build_byte_index(source, char_count),
             position: 0,
-            max_position: length - 1,
+            byte: 0,
+            line: 1,
+            line_start: 0,
             marked_position: 0,
+            mark_stack: vec![],
+            named_marks: HashMap::new(),
             state,
+            state_stack: vec![],
+            state_hooks: StateHooks::default(),
+            quote_config: QuoteConfig::default(),
+            comment_config: CommentConfig::default(),
+            scanner: Scanner::default(),
             collection: vec![],
             collection_str: "".to_owned(),
             tokens: vec![],
+            token_spans: vec![],
+            popped_tokens: vec![],
+            token_clone: None,
+            checkpoint_depth: 0,
+            errors: vec![],
             should_trace: false,
-            trace: vec![],
-        };
-        rlex
+            trace_sink: Box::new(MemorySink::new()),
+            trace_count: 0,
+            trace_depth: 0,
+            trace_filter: TraceFilter::default(),
+            trace_epoch: Instant::now(),
+        }
+    }
+
+    /// Creates a new lexer, rejecting an empty source instead of lexing it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RlexError::EmptySource`] if the source string is empty.
+    pub fn try_new(source: &'a str, state: S) -> Result<Rlex<'a, S, T>, RlexError> {
+        if source.is_empty() {
+            return Err(RlexError::EmptySource);
+        }
+        Ok(Rlex::new(source, state))
     }
 
     /// Turns on the trace system
     pub fn trace_on(&mut self) {
+        if !self.should_trace {
+            self.trace_epoch = Instant::now();
+        }
         self.should_trace = true;
     }
 
+    /// Turns on the trace system, sending events to `sink` instead of keeping them all
+    /// in memory. The sink must own what it writes to, so it never shortens the life of
+    /// slices borrowed from the source
+    pub fn trace_on_with(&mut self, sink: impl TraceSink + 'static) {
+        self.trace_sink = Box::new(sink);
+        self.trace_on();
+    }
+
     /// Turns off the trace system
     pub fn trace_off(&mut self) {
         self.should_trace = false;
     }
 
-    /// If the trace is on, will push the msg to into the trace
-    fn trace_log(&mut self, msg: &str) {
-        self.trace
-            .push(format!("{}:{}", self.trace.len(), msg.to_string() + "\n"));
+    /// Sets the filter deciding which events reach the trace sink
+    pub fn trace_filter_set(&mut self, filter: TraceFilter) {
+        self.trace_filter = filter;
+    }
+
+    /// Returns the active trace filter
+    pub fn trace_filter(&self) -> &TraceFilter {
+        &self.trace_filter
     }
 
-    /// Converts the trace into a String and returns it
+    /// Marks the start of a traced call in `category`, returning where and how deep it
+    /// started and whether the filter lets it be recorded. Callers skip formatting
+    /// arguments and results for calls that won't be.
+    fn trace_enter(&mut self, category: TraceCategory) -> TraceFrame {
+        let record = self.should_trace && self.trace_filter.admits(category, self.trace_depth);
+        let frame = TraceFrame {
+            position: self.position,
+            depth: self.trace_depth,
+            category,
+            record,
+            started: record.then(Instant::now),
+        };
+        if self.should_trace {
+            self.trace_depth += 1;
+        }
+        frame
+    }
+
+    /// Marks the end of a traced call that started at `before`, returning whether it
+    /// should be recorded with [`Rlex::trace_log`].
+    fn trace_leave(&mut self, before: &TraceFrame) -> bool {
+        self.trace_depth = before.depth;
+        before.record && self.trace_filter.overlaps(before.position, self.position)
+    }
+
+    /// Records a traced call that started at `before` into the trace.
+    fn trace_log(
+        &mut self,
+        before: TraceFrame,
+        method: &'static str,
+        args: Vec<String>,
+        result: Option<String>,
+    ) {
+        let now = Instant::now();
+        let started = before.started.unwrap_or(now);
+        let event = TraceEvent {
+            index: self.trace_count,
+            method,
+            category: before.category,
+            args,
+            result,
+            pos_before: before.position,
+            pos_after: self.position,
+            state: format!("{:?}", self.state),
+            depth: before.depth,
+            mark: self.marked_position,
+            last_token: self.tokens.last().map(|tok| format!("{:?}", tok)),
+            time: started.saturating_duration_since(self.trace_epoch),
+            duration: now.duration_since(started),
+        };
+        self.trace_sink.record(event);
+        self.trace_count += 1;
+    }
+
+    /// Returns the trace events kept by the trace sink, oldest first
+    pub fn trace_events(&self) -> Vec<&TraceEvent> {
+        self.trace_sink.events()
+    }
+
+    /// Converts the trace into a String and returns it, one event per line
     pub fn trace_emit(&self) -> String {
         let mut trace = "".to_string();
-        for s in &self.trace {
-            trace += &s;
+        for event in self.trace_sink.events() {
+            trace += &format!("{}\n", event);
         }
-        return trace;
+        trace
+    }
+
+    /// Renders every kept trace event against the source, showing the cursor, mark,
+    /// state and last token at each step
+    pub fn trace_render(&self) -> String {
+        let lines = LineIndex::new(self.source);
+        let mut out = "".to_string();
+        for event in self.trace_sink.events() {
+            out += &event.render_in(&lines);
+            out += "\n";
+        }
+        out
+    }
+
+    /// Returns a replay of the kept trace events for stepping through them
+    pub fn trace_replay(&self) -> TraceReplay<'a> {
+        let events = self.trace_sink.events().into_iter().cloned().collect();
+        TraceReplay::new(self.source, events)
+    }
+
+    /// Exports the kept trace events as JSON Lines, one object per event
+    #[cfg(feature = "trace-export")]
+    pub fn trace_export_json_lines(&self) -> String {
+        to_json_lines(self.trace_sink.events())
+    }
+
+    /// Exports the kept trace events in the Chrome trace event format, for loading into
+    /// `chrome://tracing` or Perfetto
+    #[cfg(feature = "trace-export")]
+    pub fn trace_export_chrome(&self) -> String {
+        to_chrome_trace(self.trace_sink.events())
     }
 
+    /// Clears the trace
     pub fn trace_clear(&mut self) {
-        self.trace = vec![];
+        self.trace_sink.clear();
+        self.trace_count = 0;
+        self.trace_depth = 0;
+        self.trace_epoch = Instant::now();
     }
 
     /// Get a reference to the tokens
     pub fn toks(&mut self) -> &Vec<T> {
-        if self.should_trace {
-            self.trace_log(&format!("toks() -> {:?}", self.tokens));
+        let before = self.trace_enter(TraceCategory::Tokens);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "toks", vec![], Some(format!("{:?}", self.tokens)));
         }
-        return &self.tokens;
+        &self.tokens
     }
 
     /// Get the source
-    pub fn src(&mut self) -> &str {
-        if self.should_trace {
-            self.trace_log(&format!("src()"));
+    pub fn src(&mut self) -> &'a str {
+        let before = self.trace_enter(TraceCategory::Queries);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "src", vec![], None);
         }
-        return &self.source;
+        self.source
     }
 
     /// Get the stashed tokens
     pub fn token_consume(self) -> Vec<T> {
-        return self.tokens;
+        self.tokens
+    }
+
+    /// Get the stashed tokens along with their spans
+    pub fn token_consume_spanned(self) -> Vec<Spanned<T>> {
+        self.tokens
+            .into_iter()
+            .zip(self.token_spans)
+            .map(|(tok, span)| Spanned::new(tok, span))
+            .collect()
+    }
+
+    /// Lexes the rest of the input lazily, yielding each token `lexer` produces along
+    /// with its span
+    pub fn lex<L: Lexer<'a, S, T>>(&mut self, lexer: L) -> TokenStream<'_, 'a, L, S, T> {
+        TokenStream::new(self, lexer)
+    }
+
+    /// Get the stashed tokens along with every error recorded while lexing
+    pub fn token_consume_with_errors(self) -> (Vec<T>, Vec<Diagnostic>) {
+        (self.tokens, self.errors)
+    }
+
+    /// Records an error and keeps lexing
+    pub fn error_push(&mut self, diagnostic: Diagnostic) {
+        let before = self.trace_enter(TraceCategory::Tokens);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "error_push", vec![diagnostic.to_string()], None);
+        }
+        self.errors.push(diagnostic);
+    }
+
+    /// Get the recorded errors, oldest first
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.errors
+    }
+
+    /// Checks if any errors have been recorded
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
     }
 
-    /// Adds a token to the stack.
+    /// Skips the current character and everything after it up to one of `stops` or the
+    /// end, records an error spanning the skipped text, and returns it. The lexer stops
+    /// on the stop character without consuming it.
+    pub fn recover_to(&mut self, stops: &[char]) -> &'a str {
+        self.recover_to_with(stops, "unexpected input")
+    }
+
+    /// Like [`Rlex::recover_to`], recording the error with `message`.
+    pub fn recover_to_with(&mut self, stops: &[char], message: impl Into<String>) -> &'a str {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let start = self.position;
+        self.next();
+        self.next_while(|c| !stops.contains(&c));
+        let skipped = self.slice_chars(start, self.position);
+        let diagnostic = Diagnostic::error(message, self.span_of(start, self.position));
+        self.error_push(diagnostic);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "recover_to",
+                vec![format!("{:?}", stops)],
+                Some(skipped.to_owned()),
+            );
+        }
+        skipped
+    }
+
+    /// Get the tokens paired with references to their spans
+    pub fn toks_spanned(&mut self) -> Vec<Spanned<&T>> {
+        let before = self.trace_enter(TraceCategory::Tokens);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "toks_spanned",
+                vec![],
+                Some(format!("{:?}", self.tokens)),
+            );
+        }
+        self.tokens
+            .iter()
+            .zip(self.token_spans.iter())
+            .map(|(tok, span)| Spanned::new(tok, *span))
+            .collect()
+    }
+
+    /// Adds a token to the stack, spanning the character at the current position.
     pub fn token_push(&mut self, tok: T) {
-        if self.should_trace {
-            self.trace_log(&format!("token_push({:?})", tok));
+        let before = self.trace_enter(TraceCategory::Tokens);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "token_push", vec![format!("{:?}", tok)], None);
         }
-        return self.tokens.push(tok);
+        self.state_hooks.tokens_since += 1;
+        let span = self.span_of(self.position, self.position + 1);
+        self.tokens.push(tok);
+        self.token_spans.push(span);
+    }
+
+    /// Adds a token to the stack, spanning from the marked position through the current
+    /// position, the same range as [`Rlex::str_from_mark`].
+    pub fn token_push_span(&mut self, tok: T) {
+        let before = self.trace_enter(TraceCategory::Tokens);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "token_push_span", vec![format!("{:?}", tok)], None);
+        }
+        self.state_hooks.tokens_since += 1;
+        let span = self.span_from_mark();
+        self.tokens.push(tok);
+        self.token_spans.push(span);
     }
 
     /// Removes and returns the last token.
+    ///
+    /// While a checkpoint is live, a copy of the token is kept so a rollback can put it
+    /// back.
     pub fn token_pop(&mut self) -> Option<T> {
+        let before = self.trace_enter(TraceCategory::Tokens);
         let tok = self.tokens.pop();
-        if self.should_trace {
-            self.trace_log(&format!("token_pop() -> {:?}", tok));
+        let span = self.token_spans.pop();
+        if let (Some(clone), Some(tok), Some(span)) = (self.token_clone, &tok, span) {
+            self.popped_tokens
+                .push((self.tokens.len(), clone(tok), span));
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "token_pop", vec![], Some(format!("{:?}", tok)));
         }
-        return tok;
+        tok
     }
 
     /// Returns the last token without removing it.
     pub fn token_prev(&mut self) -> Option<&T> {
-        if self.should_trace {
-            self.trace_log(&format!(
-                "token_prev() -> {:?}",
-                self.tokens.last().map(|s| s)
-            ));
+        let before = self.trace_enter(TraceCategory::Tokens);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "token_prev",
+                vec![],
+                Some(format!("{:?}", self.tokens.last())),
+            );
+        }
+        self.tokens.last()
+    }
+
+    /// Returns the span of the last token.
+    pub fn token_prev_span(&mut self) -> Option<Span> {
+        let before = self.trace_enter(TraceCategory::Tokens);
+        let span = self.token_spans.last().copied();
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "token_prev_span",
+                vec![],
+                Some(format!("{:?}", span)),
+            );
         }
-        return self.tokens.last().map(|s| s);
+        span
     }
 
     /// Returns a reference to the current state.
     pub fn state(&mut self) -> &S {
-        if self.should_trace {
-            self.trace_log(&format!("state() -> {:?}", &self.state));
+        let before = self.trace_enter(TraceCategory::State);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "state", vec![], Some(format!("{:?}", &self.state)));
         }
         &self.state
     }
 
-    /// Sets the current state.
+    /// Sets the current state, unless the state validator rejects the change.
     pub fn state_set(&mut self, state: S) {
-        if self.should_trace {
-            self.trace_log(&format!("state_set({:?})", state));
+        let before = self.trace_enter(TraceCategory::State);
+        let arg = before.record.then(|| format!("{:?}", state));
+        if self.transition_begin(&state) {
+            let from = std::mem::replace(&mut self.state, state);
+            self.transition_end(&from);
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "state_set", vec![arg.unwrap_or_default()], None);
+        }
+    }
+
+    /// Enters a nested state, saving the current one to return to with
+    /// [`Rlex::state_pop`], unless the state validator rejects the change.
+    pub fn state_push(&mut self, state: S) {
+        let before = self.trace_enter(TraceCategory::State);
+        let arg = before.record.then(|| format!("{:?}", state));
+        if self.transition_begin(&state) {
+            let outer = std::mem::replace(&mut self.state, state);
+            self.transition_end(&outer);
+            self.state_stack.push(outer);
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "state_push", vec![arg.unwrap_or_default()], None);
+        }
+    }
+
+    /// Returns to the state saved by the last [`Rlex::state_push`] and returns the state
+    /// being left, or returns `None` and keeps the current state if nothing was pushed or
+    /// the state validator rejects the change.
+    pub fn state_pop(&mut self) -> Option<S> {
+        let before = self.trace_enter(TraceCategory::State);
+        let mut left = None;
+        if let Some(outer) = self.state_stack.pop() {
+            if self.transition_begin(&outer) {
+                let inner = std::mem::replace(&mut self.state, outer);
+                self.transition_end(&inner);
+                left = Some(inner);
+            } else {
+                self.state_stack.push(outer);
+            }
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "state_pop", vec![], Some(format!("{:?}", left)));
+        }
+        left
+    }
+
+    /// Returns how many states are saved beneath the current one.
+    pub fn state_depth(&mut self) -> usize {
+        let before = self.trace_enter(TraceCategory::State);
+        let depth = self.state_stack.len();
+        if self.trace_leave(&before) {
+            self.trace_log(before, "state_depth", vec![], Some(depth.to_string()));
+        }
+        depth
+    }
+
+    /// Returns the states saved beneath the current one, outermost first.
+    pub fn state_stack(&mut self) -> &[S] {
+        let before = self.trace_enter(TraceCategory::State);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "state_stack",
+                vec![],
+                Some(format!("{:?}", self.state_stack)),
+            );
         }
-        self.state = state;
+        &self.state_stack
     }
 
     /// Returns the current character index position.
     pub fn pos(&mut self) -> usize {
-        if self.should_trace {
-            self.trace_log(&format!("pos() -> {}", self.position));
+        let before = self.trace_enter(TraceCategory::Queries);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "pos", vec![], Some(self.position.to_string()));
         }
         self.position
     }
 
-    /// Advances the lexer by one character, unless already at the end.
-    pub fn next(&mut self) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("next()"));
+    /// Returns the 1-based line of the current position.
+    pub fn line(&mut self) -> usize {
+        let before = self.trace_enter(TraceCategory::Queries);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "line", vec![], Some(self.line.to_string()));
+        }
+        self.line
+    }
+
+    /// Returns the 1-based column, counted in chars, of the current position.
+    pub fn col(&mut self) -> usize {
+        let before = self.trace_enter(TraceCategory::Queries);
+        let col = self.position - self.line_start + 1;
+        if self.trace_leave(&before) {
+            self.trace_log(before, "col", vec![], Some(col.to_string()));
+        }
+        col
+    }
+
+    /// Returns the 1-based line and column of any position, clamped to the end of input.
+    pub fn line_col_of(&mut self, pos: usize) -> (usize, usize) {
+        let before = self.trace_enter(TraceCategory::Queries);
+        let pos = pos.min(self.char_count);
+        let (mut line, mut line_start) = if pos >= self.line_start {
+            (self.line, self.line_start)
+        } else {
+            (1, 0)
+        };
+        let from = line_start;
+        let mut chars = self.source[self.byte_of(from)..].chars().peekable();
+        for i in from..pos {
+            let c = chars.next();
+            if breaks_line(c, chars.peek().copied()) {
+                line += 1;
+                line_start = i + 1;
+            }
+        }
+        let line_col = (line, pos - line_start + 1);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "line_col_of",
+                vec![pos.to_string()],
+                Some(format!("{:?}", line_col)),
+            );
+        }
+        line_col
+    }
+
+    /// Returns the char starting at byte offset `byte`, if any.
+    fn char_at_byte(&self, byte: usize) -> Option<char> {
+        self.source[byte..].chars().next()
+    }
+
+    /// Moves the cursor forward one char, keeping the line and column in step.
+    fn step_forward(&mut self) {
+        let Some(c) = self.char_at_byte(self.byte) else {
+            return;
+        };
+        let next_byte = self.byte + c.len_utf8();
+        if breaks_line(Some(c), self.char_at_byte(next_byte)) {
+            self.line += 1;
+            self.line_start = self.position + 1;
+        }
+        self.position += 1;
+        self.byte = next_byte;
+    }
+
+    /// Moves the cursor back one char, keeping the line and column in step.
+    fn step_back(&mut self) {
+        let Some(c) = self.source[..self.byte].chars().next_back() else {
+            return;
+        };
+        let after = self.char_at_byte(self.byte);
+        self.position -= 1;
+        self.byte -= c.len_utf8();
+        if breaks_line(Some(c), after) {
+            self.line -= 1;
+            let mut after = Some(c);
+            self.line_start = 0;
+            for (i, prev) in self.source[..self.byte].chars().rev().enumerate() {
+                if breaks_line(Some(prev), after) {
+                    self.line_start = self.position - i;
+                    break;
+                }
+                after = Some(prev);
+            }
         }
-        if self.position < self.max_position {
-            self.position += 1;
+    }
+
+    /// Moves the cursor to `pos`, walking from whichever of the cursor or the start is
+    /// closer so the line and column stay correct.
+    fn move_to(&mut self, pos: usize) {
+        let pos = pos.min(self.char_count);
+        if pos < self.position && pos < self.position - pos {
+            self.position = 0;
+            self.byte = 0;
+            self.line = 1;
+            self.line_start = 0;
+        }
+        while self.position < pos {
+            self.step_forward();
+        }
+        while self.position > pos {
+            self.step_back();
+        }
+    }
+
+    /// Advances the lexer by one character, unless already at the end.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        self.step_forward();
+        if self.trace_leave(&before) {
+            self.trace_log(before, "next", vec![], None);
         }
         self
     }
 
     /// Advances the lexer by a specified number of characters.
-    pub fn next_by(&mut self, by: usize) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("next_by({})", by))
-        }
+    pub fn next_by(&mut self, by: usize) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
         let mut count = 0;
         while count != by {
             self.next();
             count += 1;
         }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "next_by", vec![by.to_string()], None);
+        }
         self
     }
 
     /// Advances the lexer until a specific character is found or end is reached.
-    pub fn next_until(&mut self, search: char) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("next_until({})", search));
+    pub fn next_until(&mut self, search: char) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        while let Some(c) = self.char() {
+            if c == search {
+                break;
+            }
+            self.next();
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "next_until", vec![search.to_string()], None);
+        }
+        self
+    }
+
+    /// Advances the lexer while the current character satisfies `pred`, stopping on the
+    /// first character that does not or at the end.
+    pub fn next_while(&mut self, mut pred: impl FnMut(char) -> bool) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        while let Some(c) = self.char() {
+            if !pred(c) {
+                break;
+            }
+            self.next();
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "next_while", vec!["fn".to_owned()], None);
         }
-        while self.char() != search {
-            if self.at_end() {
+        self
+    }
+
+    /// Advances the lexer until the current character satisfies `pred` or end is reached.
+    pub fn next_until_fn(&mut self, mut pred: impl FnMut(char) -> bool) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        while let Some(c) = self.char() {
+            if pred(c) {
                 break;
             }
             self.next();
         }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "next_until_fn", vec!["fn".to_owned()], None);
+        }
         self
     }
 
+    /// Advances the lexer while the current character satisfies `pred` and returns the
+    /// consumed slice, which excludes the character the lexer stopped on.
+    pub fn take_while(&mut self, pred: impl FnMut(char) -> bool) -> &'a str {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let start = self.position;
+        self.next_while(pred);
+        let taken = self.slice_chars(start, self.position);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "take_while",
+                vec!["fn".to_owned()],
+                Some(taken.to_owned()),
+            );
+        }
+        taken
+    }
+
     /// Checks if the next character matches the given character.
     pub fn next_is(&mut self, check: char) -> bool {
-        if self.should_trace {
-            self.trace_log(&format!("next_is({})", check));
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let is_match = self.peek_opt() == Some(check);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "next_is",
+                vec![check.to_string()],
+                Some(is_match.to_string()),
+            );
         }
-        self.peek() == check
+        is_match
     }
 
     /// Checks if the character `by` positions ahead matches the given character.
     pub fn next_by_is(&mut self, check: char, by: usize) -> bool {
-        if self.should_trace {
-            self.trace_log(&format!("next_by_is({}, {})", check, by));
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let is_match = self.peek_by_opt(by) == Some(check);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "next_by_is",
+                vec![check.to_string(), by.to_string()],
+                Some(is_match.to_string()),
+            );
+        }
+        is_match
+    }
+
+    /// Checks if the source at the current position starts with `lit`.
+    pub fn starts_with_at_cursor(&mut self, lit: &str) -> bool {
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let is_match = self.match_len(lit, false).is_some();
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "starts_with_at_cursor",
+                vec![lit.to_string()],
+                Some(is_match.to_string()),
+            );
+        }
+        is_match
+    }
+
+    /// Like [`Rlex::starts_with_at_cursor`], but ignoring case.
+    pub fn starts_with_at_cursor_ignore_case(&mut self, lit: &str) -> bool {
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let is_match = self.match_len(lit, true).is_some();
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "starts_with_at_cursor_ignore_case",
+                vec![lit.to_string()],
+                Some(is_match.to_string()),
+            );
+        }
+        is_match
+    }
+
+    /// Consumes `lit` if the source at the current position starts with it.
+    pub fn eat(&mut self, lit: &str) -> bool {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let eaten = self.eat_match(lit, false).is_some();
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "eat",
+                vec![lit.to_string()],
+                Some(eaten.to_string()),
+            );
+        }
+        eaten
+    }
+
+    /// Like [`Rlex::eat`], but ignoring case.
+    pub fn eat_ignore_case(&mut self, lit: &str) -> bool {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let eaten = self.eat_match(lit, true).is_some();
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "eat_ignore_case",
+                vec![lit.to_string()],
+                Some(eaten.to_string()),
+            );
+        }
+        eaten
+    }
+
+    /// Consumes the longest of `lits` found at the current position and returns the
+    /// consumed slice. Ties go to the earliest literal; empty literals never match.
+    pub fn eat_any(&mut self, lits: &[&str]) -> Option<&'a str> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let eaten = self.eat_longest(lits, false);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "eat_any",
+                vec![format!("{:?}", lits)],
+                Some(format!("{:?}", eaten)),
+            );
+        }
+        eaten
+    }
+
+    /// Like [`Rlex::eat_any`], but ignoring case.
+    pub fn eat_any_ignore_case(&mut self, lits: &[&str]) -> Option<&'a str> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let eaten = self.eat_longest(lits, true);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "eat_any_ignore_case",
+                vec![format!("{:?}", lits)],
+                Some(format!("{:?}", eaten)),
+            );
         }
-        self.peek_by(by) == check
+        eaten
     }
 
-    /// Moves the lexer back by one character, unless at the start.
-    pub fn prev(&mut self) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("prev()"))
+    /// Returns the char and byte length of `lit` if the source at the cursor starts with it.
+    fn match_len(&self, lit: &str, ignore_case: bool) -> Option<(usize, usize)> {
+        let rest = &self.source[self.byte..];
+        if !ignore_case {
+            return rest
+                .starts_with(lit)
+                .then(|| (lit.chars().count(), lit.len()));
+        }
+        let mut rest_chars = rest.chars();
+        let (mut chars, mut bytes) = (0, 0);
+        for want in lit.chars() {
+            let got = rest_chars.next()?;
+            if !got.to_lowercase().eq(want.to_lowercase()) {
+                return None;
+            }
+            chars += 1;
+            bytes += got.len_utf8();
         }
-        if self.position > 0 {
-            self.position -= 1;
+        Some((chars, bytes))
+    }
+
+    /// Consumes `lit` on a match and returns the consumed slice.
+    fn eat_match(&mut self, lit: &str, ignore_case: bool) -> Option<&'a str> {
+        let (chars, bytes) = self.match_len(lit, ignore_case)?;
+        let eaten = &self.source[self.byte..self.byte + bytes];
+        self.next_by(chars);
+        Some(eaten)
+    }
+
+    /// Consumes the longest matching literal and returns the consumed slice.
+    fn eat_longest(&mut self, lits: &[&str], ignore_case: bool) -> Option<&'a str> {
+        let mut best: Option<&str> = None;
+        let mut best_bytes = 0;
+        for lit in lits.iter().filter(|lit| !lit.is_empty()) {
+            if let Some((_, bytes)) = self.match_len(lit, ignore_case) {
+                if bytes > best_bytes {
+                    best = Some(lit);
+                    best_bytes = bytes;
+                }
+            }
+        }
+        self.eat_match(best?, ignore_case)
+    }
+
+    /// Moves the lexer back by one character, unless at the start.
+    pub fn prev(&mut self) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        self.step_back();
+        if self.trace_leave(&before) {
+            self.trace_log(before, "prev", vec![], None);
         }
         self
     }
 
     /// Moves the lexer back by a specified number of characters.
-    pub fn prev_by(&mut self, mut by: usize) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("prev_by({})", by));
-        }
-        while by != 0 {
+    pub fn prev_by(&mut self, by: usize) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let mut count = 0;
+        while count != by {
             self.prev();
-            by -= 1;
+            count += 1;
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "prev_by", vec![by.to_string()], None);
         }
         self
     }
 
     /// Moves the lexer backward until a specific character is found or start is reached.
-    pub fn prev_until(&mut self, search: char) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("prev_until({})", search));
-        }
-        while self.char() != search {
+    pub fn prev_until(&mut self, search: char) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        while self.char() != Some(search) {
             if self.at_start() {
                 break;
             }
             self.prev();
         }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "prev_until", vec![search.to_string()], None);
+        }
+        self
+    }
+
+    /// Moves the lexer backward while the current character satisfies `pred`, stopping on
+    /// the first character that does not or at the start.
+    pub fn prev_while(&mut self, mut pred: impl FnMut(char) -> bool) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        while let Some(c) = self.char() {
+            if !pred(c) || self.at_start() {
+                break;
+            }
+            self.prev();
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "prev_while", vec!["fn".to_owned()], None);
+        }
         self
     }
 
     /// Checks if the previous character matches the given character.
     pub fn prev_is(&mut self, check: char) -> bool {
-        if self.should_trace {
-            self.trace_log(&format!("prev_is({})", check));
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let is_match = self.peek_back_opt() == Some(check);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "prev_is",
+                vec![check.to_string()],
+                Some(is_match.to_string()),
+            );
         }
-        self.peek_back() == check
+        is_match
     }
 
     /// Checks if the character `by` positions behind matches the given character.
     pub fn prev_by_is(&mut self, check: char, by: usize) -> bool {
-        if self.should_trace {
-            self.trace_log(&format!("prev_by_is({}, {})", check, by));
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let is_match = self.peek_back_by_opt(by) == Some(check);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "prev_by_is",
+                vec![check.to_string(), by.to_string()],
+                Some(is_match.to_string()),
+            );
         }
-        self.peek_back_by(by) == check
+        is_match
     }
 
-    /// Returns the character at the current position.
-    pub fn char(&mut self) -> char {
-        if self.should_trace {
-            self.trace_log(&format!("char() -> {}", self.chars[self.position]));
+    /// Returns the character at the current position, or `None` at the end of input.
+    pub fn char(&mut self) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Queries);
+        let ch = self.char_at_byte(self.byte);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "char", vec![], Some(format!("{:?}", ch)));
         }
-        self.chars[self.position]
+        ch
     }
 
-    /// Returns `true` if the lexer is at the end of the input.
+    /// Returns `true` if the lexer is past the last character of the input.
     pub fn at_end(&mut self) -> bool {
-        let is_at_end = self.position == self.max_position;
-        if self.should_trace {
-            self.trace_log(&format!("at_end() -> {}", is_at_end));
+        let before = self.trace_enter(TraceCategory::Queries);
+        let is_at_end = self.position >= self.char_count;
+        if self.trace_leave(&before) {
+            self.trace_log(before, "at_end", vec![], Some(is_at_end.to_string()));
         }
         is_at_end
     }
 
     /// Returns `true` if the lexer is at the beginning of the input.
     pub fn at_start(&mut self) -> bool {
+        let before = self.trace_enter(TraceCategory::Queries);
         let is_at_start = self.position == 0;
-        if self.should_trace {
-            self.trace_log(&format!("at_start() -> {}", is_at_start));
+        if self.trace_leave(&before) {
+            self.trace_log(before, "at_start", vec![], Some(is_at_start.to_string()));
         }
         is_at_start
     }
 
     /// Returns `true` if the current position is at the marked position.
     pub fn at_mark(&mut self) -> bool {
+        let before = self.trace_enter(TraceCategory::Queries);
         let is_at_mark = self.marked_position == self.position;
-        if self.should_trace {
-            self.trace_log(&format!("at_mark() -> {}", is_at_mark));
+        if self.trace_leave(&before) {
+            self.trace_log(before, "at_mark", vec![], Some(is_at_mark.to_string()));
         }
         is_at_mark
     }
 
     /// Marks the current position.
-    pub fn mark(&mut self) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("mark()"));
-        }
+    pub fn mark(&mut self) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
         self.marked_position = self.position;
+        if self.trace_leave(&before) {
+            self.trace_log(before, "mark", vec![], None);
+        }
         self
     }
 
-    /// Moves the current position to a specific index.
-    pub fn goto_pos(&mut self, pos: usize) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("goto_pos({})", pos));
-        }
-        if pos > self.max_position {
-            self.position = self.max_position;
-            return self;
+    /// Moves the current position to a specific index, clamped to the end of input.
+    pub fn goto_pos(&mut self, pos: usize) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        self.move_to(pos);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "goto_pos", vec![pos.to_string()], None);
         }
-        self.position = pos;
         self
     }
 
     /// Moves the current position back to the previously marked index.
-    pub fn goto_mark(&mut self) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("goto_mark()"));
+    pub fn goto_mark(&mut self) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        self.move_to(self.marked_position);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "goto_mark", vec![], None);
+        }
+        self
+    }
+
+    /// Pushes the current position onto the mark stack, leaving [`Rlex::mark`] untouched.
+    pub fn mark_push(&mut self) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        self.mark_stack.push(self.cursor());
+        if self.trace_leave(&before) {
+            self.trace_log(before, "mark_push", vec![], None);
+        }
+        self
+    }
+
+    /// Pops the top of the mark stack and returns its position.
+    pub fn mark_pop(&mut self) -> Option<usize> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let pos = self.mark_stack.pop().map(|c| c.position);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "mark_pop", vec![], Some(format!("{:?}", pos)));
+        }
+        pos
+    }
+
+    /// Returns a string slice between the top of the mark stack and the current position.
+    pub fn str_from_mark_top(&self) -> Option<&'a str> {
+        self.mark_stack
+            .last()
+            .map(|c| &self.source[self.span_between(c.position, self.position).byte_range()])
+    }
+
+    /// Records the current position under `name`, replacing any mark already using it.
+    pub fn mark_as(&mut self, name: &str) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        self.named_marks.insert(name.to_owned(), self.cursor());
+        if self.trace_leave(&before) {
+            self.trace_log(before, "mark_as", vec![name.to_string()], None);
         }
-        self.position = self.marked_position;
         self
     }
 
+    /// Moves the current position to the mark recorded under `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RlexError::UnknownMark`] if no mark has that name.
+    pub fn goto_named(&mut self, name: &str) -> Result<&Rlex<'a, S, T>, RlexError> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        let cursor = self.named_marks.get(name).copied();
+        if let Some(cursor) = cursor {
+            self.restore(cursor);
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "goto_named",
+                vec![name.to_string()],
+                Some(cursor.is_some().to_string()),
+            );
+        }
+        match cursor {
+            Some(_) => Ok(self),
+            None => Err(RlexError::UnknownMark(name.to_owned())),
+        }
+    }
+
+    /// Returns a string slice between the mark recorded under `name` and the current
+    /// position.
+    pub fn str_from_named(&self, name: &str) -> Option<&'a str> {
+        self.named_marks
+            .get(name)
+            .map(|c| &self.source[self.span_between(c.position, self.position).byte_range()])
+    }
+
+    /// Captures the cursor so it can be restored without walking the source.
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            position: self.position,
+            byte: self.byte,
+            line: self.line,
+            line_start: self.line_start,
+        }
+    }
+
+    /// Restores a cursor captured by [`Rlex::cursor`].
+    fn restore(&mut self, cursor: Cursor) {
+        self.position = cursor.position;
+        self.byte = cursor.byte;
+        self.line = cursor.line;
+        self.line_start = cursor.line_start;
+    }
+
     /// Moves the current position to the start of the input.
-    pub fn goto_start(&mut self) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("goto_start()"));
+    pub fn goto_start(&mut self) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        self.move_to(0);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "goto_start", vec![], None);
         }
-        self.position = 0;
         self
     }
 
-    /// Moves the current position to the end of the input.
-    pub fn goto_end(&mut self) -> &Rlex<S, T> {
-        if self.should_trace {
-            self.trace_log(&format!("goto_end()"));
+    /// Moves the current position past the last character of the input.
+    pub fn goto_end(&mut self) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        self.move_to(self.char_count);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "goto_end", vec![], None);
         }
-        self.position = self.max_position;
         self
     }
 
     /// Peeks at the next character without advancing the position.
-    pub fn peek(&mut self) -> char {
+    pub fn peek(&mut self) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Peeks);
         let start = self.position;
         self.next();
         let ch = self.char();
         self.goto_pos(start);
-        if self.should_trace {
-            self.trace_log(&format!("peek() -> {}", ch));
+        if self.trace_leave(&before) {
+            self.trace_log(before, "peek", vec![], Some(format!("{:?}", ch)));
         }
         ch
     }
 
     /// Peeks ahead by `by` characters without advancing the position.
-    pub fn peek_by(&mut self, by: usize) -> char {
+    pub fn peek_by(&mut self, by: usize) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Peeks);
         let start = self.position;
         self.next_by(by);
         let ch = self.char();
         self.goto_pos(start);
-        if self.should_trace {
-            self.trace_log(&format!("peek_by({}) -> {}", by, ch));
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "peek_by",
+                vec![by.to_string()],
+                Some(format!("{:?}", ch)),
+            );
         }
         ch
     }
 
     /// Peeks at the previous character without changing the position.
-    pub fn peek_back(&mut self) -> char {
+    ///
+    /// Clamps at the start of input, so at position 0 this returns the first character.
+    /// Use [`Rlex::peek_back_opt`] to get `None` instead.
+    pub fn peek_back(&mut self) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Peeks);
         let start = self.position;
         self.prev();
         let ch = self.char();
         self.goto_pos(start);
-        if self.should_trace {
-            self.trace_log(&format!("peek_back() -> {}", ch));
+        if self.trace_leave(&before) {
+            self.trace_log(before, "peek_back", vec![], Some(format!("{:?}", ch)));
         }
         ch
     }
 
     /// Peeks behind by `by` characters without changing the position.
-    pub fn peek_back_by(&mut self, by: usize) -> char {
+    ///
+    /// Clamps at the start of input. Use [`Rlex::peek_back_by_opt`] to get `None` instead.
+    pub fn peek_back_by(&mut self, by: usize) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Peeks);
         let start = self.position;
         self.prev_by(by);
         let ch = self.char();
         self.goto_pos(start);
-        if self.should_trace {
-            self.trace_log(&format!("peek_back_by({}) -> {}", by, ch));
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "peek_back_by",
+                vec![by.to_string()],
+                Some(format!("{:?}", ch)),
+            );
         }
         ch
     }
 
-    /// Returns a string slice from the source based on start and end positions.
-    pub fn str_from_rng(&self, mut start: usize, mut end: usize) -> &str {
-        if start > self.max_position {
-            start = self.max_position;
+    /// Peeks at the next character, returning `None` if it is past the end of input.
+    pub fn peek_opt(&mut self) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let ch = self.source[self.byte..].chars().nth(1);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "peek_opt", vec![], Some(format!("{:?}", ch)));
         }
-        if end > self.max_position {
-            end = self.max_position;
+        ch
+    }
+
+    /// Peeks ahead by `by` characters, returning `None` if that is past the end of input.
+    pub fn peek_by_opt(&mut self, by: usize) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let ch = self.source[self.byte..].chars().nth(by);
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "peek_by_opt",
+                vec![by.to_string()],
+                Some(format!("{:?}", ch)),
+            );
         }
-        if start > end {
-            std::mem::swap(&mut start, &mut end);
+        ch
+    }
+
+    /// Peeks at the previous character, returning `None` at the start of input.
+    pub fn peek_back_opt(&mut self) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let ch = self.source[..self.byte].chars().next_back();
+        if self.trace_leave(&before) {
+            self.trace_log(before, "peek_back_opt", vec![], Some(format!("{:?}", ch)));
         }
-        let start_byte = self.chars[..start]
-            .iter()
-            .map(|c| c.len_utf8())
-            .sum::<usize>();
-        let byte_len = self.chars[start..=end]
-            .iter()
-            .map(|c| c.len_utf8())
-            .sum::<usize>();
-        let str = &self.source[start_byte..start_byte + byte_len];
-        return str;
+        ch
     }
 
-    /// Returns a string slice between the marked position and the current position.
-    pub fn str_from_mark(&self) -> &str {
-        let (start, end) = if self.marked_position <= self.position {
-            (self.marked_position, self.position)
-        } else {
-            (self.position, self.marked_position)
+    /// Peeks behind by `by` characters, returning `None` if that is before the start of
+    /// input.
+    pub fn peek_back_by_opt(&mut self, by: usize) -> Option<char> {
+        let before = self.trace_enter(TraceCategory::Peeks);
+        let ch = match by {
+            0 => self.char_at_byte(self.byte),
+            _ => self.source[..self.byte].chars().rev().nth(by - 1),
         };
-        let start_byte = self.chars[..start]
-            .iter()
-            .map(|c| c.len_utf8())
-            .sum::<usize>();
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "peek_back_by_opt",
+                vec![by.to_string()],
+                Some(format!("{:?}", ch)),
+            );
+        }
+        ch
+    }
 
-        let byte_len = self.chars[start..=end]
-            .iter()
-            .map(|c| c.len_utf8())
-            .sum::<usize>();
-        &self.source[start_byte..start_byte + byte_len]
+    /// Returns the source slice covering the char range `start..end`, clamped to the input.
+    fn slice_chars(&self, start: usize, end: usize) -> &'a str {
+        let span = self.span_of(start, end);
+        &self.source[span.byte_range()]
     }
 
-    /// Returns a string slice from the start up to the current position.
-    pub fn str_from_start(&self) -> &str {
-        let start = 0;
-        let end = self.position.min(self.max_position) + 1;
-        let start_byte = self.chars[start..end]
-            .iter()
-            .map(|c| c.len_utf8())
-            .take(start)
-            .sum::<usize>();
-        let byte_len = self.chars[start..end]
-            .iter()
+    /// Returns the byte offset of the char at `pos`, or the source length at the end.
+    ///
+    /// Starts from the nearest checkpoint in the byte index, so at most
+    /// `BYTE_INDEX_STRIDE - 1` chars are walked.
+    fn byte_of(&self, pos: usize) -> usize {
+        let pos = pos.min(self.char_count);
+        if pos == self.position {
+            return self.byte;
+        }
+        if self.byte_index.is_empty() {
+            return pos;
+        }
+        let checkpoint = pos / BYTE_INDEX_STRIDE;
+        let base = self.byte_index[checkpoint];
+        base + self.source[base..]
+            .chars()
+            .take(pos - checkpoint * BYTE_INDEX_STRIDE)
             .map(|c| c.len_utf8())
-            .sum::<usize>();
-        &self.source[start_byte..start_byte + byte_len]
+            .sum::<usize>()
+    }
+
+    /// Returns the span covering the char range `start..end`, clamped to the input.
+    pub fn span_of(&self, start: usize, end: usize) -> Span {
+        let end = end.min(self.char_count);
+        let start = start.min(end);
+        Span {
+            start,
+            end,
+            start_byte: self.byte_of(start),
+            end_byte: self.byte_of(end),
+        }
     }
 
-    /// Returns a string slice from the current position to the end.
-    pub fn str_from_end(&self) -> &str {
-        let start = self.position;
-        let end = self.max_position + 1;
-        let start_byte = self.chars[..start]
-            .iter()
-            .map(|c| c.len_utf8())
-            .sum::<usize>();
-        let byte_len = self.chars[start..end]
-            .iter()
-            .map(|c| c.len_utf8())
-            .sum::<usize>();
-        &self.source[start_byte..start_byte + byte_len]
+    /// Returns the span between the marked position and the current position, inclusive
+    /// of both, matching [`Rlex::str_from_mark`].
+    pub fn span_from_mark(&self) -> Span {
+        self.span_between(self.marked_position, self.position)
     }
 
-    /// Checks whether the lexer is currently inside a quoted string.
-    pub fn is_in_quote(&mut self) -> bool {
-        let mut in_big_quote = false;
-        let mut in_lil_quote = false;
-        let mut escaped = false;
-        for c in self.str_from_start().chars() {
-            if escaped {
-                escaped = false;
-                continue;
-            }
-            if c == '\\' {
-                escaped = true;
-            } else if c == '"' {
-                in_big_quote = !in_big_quote;
-            } else if c == '\'' {
-                in_lil_quote = !in_lil_quote;
+    /// Creates an error diagnostic spanning the current character.
+    pub fn error_here(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(message, self.span_of(self.position, self.position + 1))
+    }
+
+    /// Creates an error diagnostic spanning from the marked position through the current
+    /// position, the same range as [`Rlex::str_from_mark`].
+    pub fn error_from_mark(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(message, self.span_from_mark())
+    }
+
+    /// Renders a diagnostic against the lexer's source.
+    pub fn diagnostic_render(&self, diagnostic: &Diagnostic) -> String {
+        diagnostic.render(self.source)
+    }
+
+    /// Returns the span between two positions in either order, inclusive of both.
+    fn span_between(&self, a: usize, b: usize) -> Span {
+        self.span_of(a.min(b), a.max(b) + 1)
+    }
+
+    /// Returns a string slice from the source based on inclusive start and end positions.
+    pub fn str_from_rng(&self, mut start: usize, mut end: usize) -> &'a str {
+        if start > end {
+            std::mem::swap(&mut start, &mut end);
+        }
+        self.slice_chars(start, end.saturating_add(1))
+    }
+
+    /// Returns a string slice between the marked position and the current position.
+    pub fn str_from_mark(&self) -> &'a str {
+        &self.source[self.span_from_mark().byte_range()]
+    }
+
+    /// Returns a string slice from the start up to the current position.
+    pub fn str_from_start(&self) -> &'a str {
+        self.slice_chars(0, self.position + 1)
+    }
+
+    /// Returns a string slice from the current position to the end.
+    pub fn str_from_end(&self) -> &'a str {
+        self.slice_chars(self.position, self.char_count)
+    }
+
+    /// Sets the quote rules used by [`Rlex::is_in_quote`].
+    pub fn quote_config_set(&mut self, config: QuoteConfig) {
+        let before = self.trace_enter(TraceCategory::State);
+        self.quote_config = config;
+        self.scanner = Scanner::default();
+        if self.trace_leave(&before) {
+            let arg = format!("{:?}", self.quote_config);
+            self.trace_log(before, "quote_config_set", vec![arg], None);
+        }
+    }
+
+    /// Returns the quote rules used by [`Rlex::is_in_quote`].
+    pub fn quote_config(&self) -> &QuoteConfig {
+        &self.quote_config
+    }
+
+    /// Sets the comment rules used by [`Rlex::is_in_comment`].
+    pub fn comment_config_set(&mut self, config: CommentConfig) {
+        let before = self.trace_enter(TraceCategory::State);
+        self.comment_config = config;
+        self.scanner = Scanner::default();
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "comment_config_set",
+                vec![format!("{:?}", self.comment_config)],
+                None,
+            );
+        }
+    }
+
+    /// Returns the comment rules used by [`Rlex::is_in_comment`].
+    pub fn comment_config(&self) -> &CommentConfig {
+        &self.comment_config
+    }
+
+    /// Brings the quote, comment and bracket scanner up to the cursor, counting the
+    /// character under it. Scanning resumes where the last query stopped unless the
+    /// cursor is behind it.
+    fn scan_to_cursor(&mut self) -> &Scanner {
+        let count = (self.position + 1).min(self.char_count);
+        let rules = ScanRules {
+            quotes: &self.quote_config,
+            comments: &self.comment_config,
+        };
+        self.scanner.advance_to(self.source, &rules, count);
+        &self.scanner
+    }
+
+    /// Checks whether the character under the cursor is part of a comment, delimiters
+    /// included. Quotes inside comments and comment delimiters inside quotes are literal.
+    /// At the end of input, checks whether a comment was left open.
+    pub fn is_in_comment(&mut self) -> bool {
+        let before = self.trace_enter(TraceCategory::Queries);
+        let at_end = self.position >= self.char_count;
+        let scanner = self.scan_to_cursor();
+        let result = if at_end {
+            scanner.comment_open()
+        } else {
+            scanner.last_in_comment()
+        };
+        if self.trace_leave(&before) {
+            self.trace_log(before, "is_in_comment", vec![], Some(result.to_string()));
+        }
+        result
+    }
+
+    /// Advances the lexer past any whitespace and comments.
+    pub fn skip_comments_and_whitespace(&mut self) -> &Rlex<'a, S, T> {
+        let before = self.trace_enter(TraceCategory::Navigation);
+        while let Some(c) = self.char() {
+            if !c.is_whitespace() && !self.is_in_comment() {
+                break;
             }
+            self.next();
         }
-		let result = in_big_quote || in_lil_quote;
-		if self.should_trace {
-			self.trace_log(&format!("is_in_quote() -> {}", result));
-		}
-        in_big_quote || in_lil_quote
+        if self.trace_leave(&before) {
+            self.trace_log(before, "skip_comments_and_whitespace", vec![], None);
+        }
+        self
     }
 
-    /// Adds the current character to the internal collection buffer.
+    /// Checks whether the lexer is currently inside a quoted string, counting the
+    /// character under the cursor. Quote rules come from [`Rlex::quote_config_set`].
+    pub fn is_in_quote(&mut self) -> bool {
+        let before = self.trace_enter(TraceCategory::Queries);
+        let result = self.scan_to_cursor().in_quote();
+        if self.trace_leave(&before) {
+            self.trace_log(before, "is_in_quote", vec![], Some(result.to_string()));
+        }
+        result
+    }
+
+    /// Returns how deeply the cursor is nested in `()`, `[]` or `{}`, picked by either
+    /// bracket of the pair, counting the character under the cursor. Brackets inside
+    /// quotes are ignored, and any other character returns 0.
+    pub fn depth_of(&mut self, bracket: char) -> usize {
+        let before = self.trace_enter(TraceCategory::Queries);
+        let depth = match scan::bracket_index(bracket) {
+            Some(i) => self.scan_to_cursor().depth(i),
+            None => 0,
+        };
+        if self.trace_leave(&before) {
+            self.trace_log(
+                before,
+                "depth_of",
+                vec![bracket.to_string()],
+                Some(depth.to_string()),
+            );
+        }
+        depth
+    }
+
+    /// Adds the current character to the internal collection buffer, if there is one.
     pub fn collect(&mut self) {
-        if self.should_trace {
-            self.trace_log(&format!("collect()"));
+        let before = self.trace_enter(TraceCategory::Collection);
+        if let Some(c) = self.char() {
+            self.collection.push(c);
+        }
+        if self.trace_leave(&before) {
+            self.trace_log(before, "collect", vec![], None);
         }
-        let char = self.char();
-        self.collection.push(char);
     }
 
     /// Returns the string collected so far from the buffer.
@@ -500,29 +1523,73 @@ where
 
     /// Clears the internal character collection buffer.
     pub fn collect_clear(&mut self) {
-		if self.should_trace {
-			self.trace_log(&format!("collect_clear()"))
-		}
+        let before = self.trace_enter(TraceCategory::Collection);
         self.collection = vec![];
         self.collection_str = "".to_owned();
+        if self.trace_leave(&before) {
+            self.trace_log(before, "collect_clear", vec![], None);
+        }
     }
 
     /// Removes and returns the last character from the collection buffer.
     pub fn collect_pop(&mut self) -> Option<char> {
-		let option = self.collection.pop();
-		if self.should_trace {
-			self.trace_log(&format!("collect_pop() -> {:?}", option));
-		}
-		option
+        let before = self.trace_enter(TraceCategory::Collection);
+        let option = self.collection.pop();
+        if self.trace_leave(&before) {
+            self.trace_log(before, "collect_pop", vec![], Some(format!("{:?}", option)));
+        }
+        option
     }
 
     /// Adds a character to the collection buffer.
     pub fn collect_push(&mut self, c: char) {
-		if self.should_trace {
-			self.trace_log(&format!("collect_push({})", c));
-		}
+        let before = self.trace_enter(TraceCategory::Collection);
         self.collection.push(c);
+        if self.trace_leave(&before) {
+            self.trace_log(before, "collect_push", vec![c.to_string()], None);
+        }
+    }
+}
+
+/// A saved cursor, including the byte offset and line so it restores in constant time.
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    position: usize,
+    byte: usize,
+    line: usize,
+    line_start: usize,
+}
+
+/// Returns `true` if `c`, followed by `next`, ends a line. `\r\n` ends on the `\n`,
+/// while a lone `\r` ends a line on its own.
+fn breaks_line(c: Option<char>, next: Option<char>) -> bool {
+    match c {
+        Some('\n') => true,
+        Some('\r') => next != Some('\n'),
+        _ => false,
+    }
+}
+
+/// Number of chars between checkpoints in the char-to-byte index.
+const BYTE_INDEX_STRIDE: usize = 64;
+
+/// Builds a sparse char-to-byte index holding the byte offset of every
+/// `BYTE_INDEX_STRIDE`th char. ASCII sources get an empty index, since their char and
+/// byte offsets are equal.
+fn build_byte_index(source: &str, char_count: usize) -> Vec<usize> {
+    if source.is_ascii() {
+        return vec![];
+    }
+    let mut index = Vec::with_capacity(char_count / BYTE_INDEX_STRIDE + 1);
+    for (i, (byte, _)) in source.char_indices().enumerate() {
+        if i % BYTE_INDEX_STRIDE == 0 {
+            index.push(byte);
+        }
     }
+    if char_count.is_multiple_of(BYTE_INDEX_STRIDE) {
+        index.push(source.len());
+    }
+    index
 }
 
 /// A public default state for when you want an Rlex and don't care about the state
@@ -537,23 +1604,47 @@ pub enum DefaultToken {
     Default,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum State {
-    Init,
-    Open,
-    Closed,
-}
-
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum Token {
-    Tok1,
-    Tok2,
-    Tok3,
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[allow(dead_code)]
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    enum State {
+        Init,
+        Open,
+        Closed,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    enum Token {
+        Tok1,
+        Tok2,
+        Tok3,
+    }
+
+    /// A writer whose output can still be read after the lexer owning it is dropped.
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
     #[test]
     fn test_trace() {
@@ -570,6 +1661,593 @@ mod tests {
         assert!(r.trace_emit() == "0:token_push(Tok1)\n");
     }
 
+    #[test]
+    fn test_trace_events() {
+        let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
+        r.trace_on();
+        r.next_by(2);
+        r.state_set(State::Open);
+        r.peek_opt();
+        let events = r.trace_events();
+        assert!(events.len() == 5);
+        assert!(events[0].method == "next" && events[0].pos_before == 0);
+        assert!(events[0].pos_after == 1);
+        let next_by = &events[2];
+        assert!(next_by.index == 2 && next_by.method == "next_by");
+        assert!(next_by.args == vec!["2".to_owned()]);
+        assert!(next_by.result.is_none());
+        assert!(next_by.pos_before == 0 && next_by.pos_after == 2);
+        assert!(next_by.state == "Init");
+        assert!(events[3].state == "Open");
+        assert!(events[4].result == Some("Some('d')".to_owned()));
+        assert!(
+            r.trace_emit()
+                == "0:next()\n1:next()\n2:next_by(2)\n3:state_set(Open)\n4:peek_opt() -> Some('d')\n"
+        );
+    }
+
+    #[test]
+    fn test_trace_sinks() {
+        let out = SharedBuf::default();
+        let source = "abcd".to_owned();
+        let mut r: Rlex<State, Token> = Rlex::new(&source, State::Init);
+        r.trace_on_with(WriterSink::new(out.clone()));
+        let first = r.take_while(|c| c == 'a');
+        r.peek_opt();
+        assert!(r.trace_events().is_empty());
+        drop(r);
+        assert!(first == "a");
+        assert!(out.text().starts_with("0:char() -> Some('a')\n"));
+        assert!(out.text().ends_with("peek_opt() -> Some('c')\n"));
+        let methods = Rc::new(RefCell::new(vec![]));
+        let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
+        let log = methods.clone();
+        r.trace_on_with(CallbackSink::new(move |event: TraceEvent| {
+            log.borrow_mut().push(event.method)
+        }));
+        r.next_by(2);
+        assert!(*methods.borrow() == vec!["next", "next", "next_by"]);
+        let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
+        r.trace_on_with(RingSink::new(2));
+        r.next_by(3);
+        assert!(r.trace_emit() == "2:next()\n3:next_by(3)\n");
+        r.trace_on_with(NoopSink);
+        r.next();
+        assert!(r.trace_emit().is_empty());
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        let mut r: Rlex<State, Token> = Rlex::new("abcdxefgh", State::Init);
+        r.trace_on();
+        r.next_until('x');
+        assert!(r
+            .trace_events()
+            .iter()
+            .any(|e| e.method == "char" && e.depth == 1));
+        assert!(r.trace_events().last().unwrap().depth == 0);
+        r.trace_clear();
+        r.goto_start();
+        r.trace_filter_set(TraceFilter::new().top_level_only());
+        r.next_until('x');
+        r.peek_opt();
+        assert!(r.trace_emit() == "0:goto_start()\n1:next_until(x)\n2:peek_opt() -> Some('e')\n");
+        r.trace_clear();
+        r.trace_filter_set(TraceFilter::new().only(&[TraceCategory::Peeks, TraceCategory::Tokens]));
+        r.next();
+        r.peek_opt();
+        r.at_end();
+        r.token_push(Token::Tok1);
+        assert!(r.trace_emit() == "0:peek_opt() -> Some('f')\n1:token_push(Tok1)\n");
+        r.trace_clear();
+        r.goto_start();
+        r.trace_filter_set(TraceFilter::new().within(6..8).top_level_only());
+        for _ in 0..9 {
+            r.next();
+        }
+        r.goto_start();
+        assert!(r.trace_emit() == "0:next()\n1:next()\n2:next()\n3:goto_start()\n");
+        r.trace_clear();
+        r.trace_filter_set(TraceFilter::new().top_level_only());
+        r.next_until('x');
+        r.collect_push('x');
+        r.state_set(State::Open);
+        let categories: Vec<_> = r.trace_events().iter().map(|e| e.category).collect();
+        assert!(
+            categories
+                == vec![
+                    TraceCategory::Navigation,
+                    TraceCategory::Collection,
+                    TraceCategory::State
+                ]
+        );
+    }
+
+    #[test]
+    fn test_trace_replay() {
+        let src = "let x\n= 10;";
+        let mut r: Rlex<State, Token> = Rlex::new(src, State::Init);
+        r.trace_on();
+        r.trace_filter_set(TraceFilter::new().top_level_only());
+        r.next_by(4);
+        r.mark();
+        r.token_push(Token::Tok1);
+        r.next_by(3);
+        r.state_set(State::Open);
+        assert!(
+            r.trace_events()[0].render(src)
+                == "0:next_by(4)\n1 | let x\n  |     ^ cursor\n  | ^ mark\n  = state: Init\n  = last token: none\n"
+        );
+        let mut replay = r.trace_replay();
+        assert!(replay.len() == 5 && replay.index() == 0);
+        assert!(replay.step_back().is_none());
+        assert!(replay.step_forward().unwrap().method == "mark");
+        assert!(
+            replay.render()
+                == "1:mark()\n1 | let x\n  |     ^ cursor\n  |     ^ mark\n  = state: Init\n  = last token: none\n"
+        );
+        assert!(replay.seek(4).unwrap().method == "state_set");
+        assert!(
+            replay.render()
+                == "4:state_set(Open)\n2 | = 10;\n  |  ^ cursor\n  = mark: 1:5\n  = state: Open\n  = last token: Tok1\n"
+        );
+        assert!(replay.step_forward().is_none() && replay.index() == 4);
+        assert!(replay.step_back().unwrap().method == "next_by");
+        assert!(replay.seek(5).is_none() && replay.index() == 3);
+        assert!(r.trace_render().starts_with("0:next_by(4)\n1 | let x\n"));
+        let src = "ab\r\ncé\rd\n\nef";
+        let lines = snippet::LineIndex::new(src);
+        for pos in 0..=src.chars().count() + 1 {
+            assert!(lines.line_at(pos) == snippet::line_at(src, pos));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "trace-export")]
+    fn test_trace_export() {
+        let mut r: Rlex<State, Token> = Rlex::new("a\"b", State::Init);
+        r.trace_on();
+        r.trace_filter_set(TraceFilter::new().top_level_only());
+        r.next_until('"');
+        r.char();
+        let lines = r.trace_export_json_lines();
+        let lines: Vec<&str> = lines.lines().collect();
+        assert!(lines.len() == 2);
+        assert!(lines[0].starts_with(
+            "{\"index\":0,\"method\":\"next_until\",\"category\":\"navigation\",\"args\":[\"\\\"\"],\"result\":null,\"pos_before\":0,\"pos_after\":1,\"mark\":0,\"state\":\"Init\",\"last_token\":null,\"depth\":0,\"time_us\":"
+        ));
+        assert!(lines[1].contains("\"result\":\"Some('\\\"')\""));
+        let chrome = r.trace_export_chrome();
+        assert!(chrome.starts_with("{\"traceEvents\":[{\"name\":\"next_until\",\"cat\":\"navigation\",\"ph\":\"X\",\"ts\":"));
+        assert!(chrome.ends_with("],\"displayTimeUnit\":\"ns\"}"));
+        assert!(chrome.matches("\"ph\":\"X\"").count() == 2);
+        let out = SharedBuf::default();
+        let mut r: Rlex<State, Token> = Rlex::new("ab", State::Init);
+        r.trace_on_with(JsonLinesSink::new(out.clone()));
+        r.next();
+        assert!(out.text().starts_with("{\"index\":0,\"method\":\"next\""));
+    }
+
+    #[test]
+    fn test_trace_timing() {
+        let mut r: Rlex<State, Token> = Rlex::new("abcdxefgh", State::Init);
+        r.trace_on();
+        r.next_until('x');
+        let events = r.trace_events();
+        let outer = events.last().unwrap();
+        assert!(events.iter().all(|e| e.time >= outer.time));
+        assert!(events
+            .iter()
+            .all(|e| e.time + e.duration <= outer.time + outer.duration));
+    }
+
+    #[test]
+    fn test_diagnostic() {
+        let mut r: Rlex<State, Token> = Rlex::new("let s = \"abc\nlet t = 1;", State::Init);
+        r.next_until('"');
+        r.mark();
+        r.next_until('\n');
+        let d = r
+            .error_from_mark("unterminated string")
+            .with_note("strings must be closed on the line they start");
+        assert!(d.severity == Severity::Error && d.to_string() == "error: unterminated string");
+        assert!(
+            r.diagnostic_render(&d)
+                == "error: unterminated string\n --> 1:9\n  |\n1 | let s = \"abc\n  |         ^^^^^\n  |\n  = note: strings must be closed on the line they start\n"
+        );
+        r.next();
+        let d = r
+            .error_here("unexpected `l`")
+            .with_label(r.span_of(8, 9), "string opened here");
+        assert!(
+            r.diagnostic_render(&d)
+                == "error: unexpected `l`\n --> 2:1\n  |\n1 | let s = \"abc\n  |         - string opened here\n2 | let t = 1;\n  | ^\n"
+        );
+        let src = "a\nb\r\nc\nd\ne\nf\ng";
+        let r: Rlex<State, Token> = Rlex::new(src, State::Init);
+        let d = Diagnostic::warning("long", r.span_of(0, 14)).with_label(r.span_of(2, 3), "b");
+        assert!(
+            r.diagnostic_render(&d)
+                == "warning: long\n --> 1:1\n  |\n1 | a\n  | ^^\n2 | b\n  | ^^\n  | - b\n...\n6 | f\n  | ^^\n7 | g\n  | ^\n"
+        );
+        let r: Rlex<State, Token> = Rlex::new("ab\r\ncd", State::Init);
+        let d = Diagnostic::error("newline", r.span_of(3, 4));
+        assert!(r.diagnostic_render(&d) == "error: newline\n --> 1:4\n  |\n1 | ab\n  |   ^\n");
+        let r: Rlex<State, Token> = Rlex::new("", State::Init);
+        assert!(
+            r.diagnostic_render(&r.error_here("empty"))
+                == "error: empty\n --> 1:1\n  |\n1 |\n  | ^\n"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut r: Rlex<State, Token> = Rlex::new("a @# b;\nc", State::Init);
+        assert!(!r.has_errors());
+        r.next_by(2);
+        assert!(r.recover_to(&[';', '\n']) == "@# b");
+        assert!(r.char() == Some(';'));
+        r.next();
+        assert!(r.recover_to(&[';', '\n']) == "\nc");
+        assert!(r.at_end());
+        assert!(r.recover_to_with(&[';'], "trailing").is_empty());
+        assert!(r.has_errors() && r.errors().len() == 3);
+        assert!(r.errors()[0].span == r.span_of(2, 6));
+        assert!(r.errors()[0].message == "unexpected input");
+        assert!(r.errors()[2].message == "trailing" && r.errors()[2].span.is_empty());
+        r.goto_start();
+        let cp = r.checkpoint();
+        r.error_push(r.error_here("bad"));
+        r.token_push(Token::Tok1);
+        r.rollback(&cp);
+        assert!(r.errors().len() == 3);
+        r.token_push(Token::Tok2);
+        let (toks, errors) = r.token_consume_with_errors();
+        assert!(toks == vec![Token::Tok2] && errors.len() == 3);
+    }
+
+    #[test]
+    fn test_lex() {
+        #[derive(Debug, PartialEq)]
+        enum Tok<'a> {
+            Num(&'a str),
+            Ident(&'a str),
+            Plus,
+        }
+        fn lex_one<'a>(r: &mut Rlex<'a, State, Tok<'a>>) -> Option<Tok<'a>> {
+            match r.char()? {
+                c if c.is_ascii_digit() => Some(Tok::Num(r.take_while(|c| c.is_ascii_digit()))),
+                c if c.is_alphabetic() => Some(Tok::Ident(r.take_while(|c| c.is_alphanumeric()))),
+                '+' => {
+                    r.next();
+                    Some(Tok::Plus)
+                }
+                c if c.is_whitespace() => {
+                    r.next_while(|c| c.is_whitespace());
+                    None
+                }
+                _ => None,
+            }
+        }
+        let mut r: Rlex<State, Tok> = Rlex::new("12 + abc ? 3", State::Init);
+        let toks: Vec<Spanned<Tok>> = r.lex(lex_one).collect();
+        assert!(toks.len() == 4);
+        assert!(toks[0] == Spanned::new(Tok::Num("12"), r.span_of(0, 2)));
+        assert!(toks[2] == Spanned::new(Tok::Ident("abc"), r.span_of(5, 8)));
+        assert!(toks[3].value == Tok::Num("3"));
+        assert!(r.errors().len() == 1 && r.errors()[0].span == r.span_of(9, 10));
+        let mut r: Rlex<State, Tok> = Rlex::new("1 + 2 + 3", State::Init);
+        let mut stream = r.lex(lex_one).filter(|t| t.value != Tok::Plus).peekable();
+        assert!(stream.peek().unwrap().value == Tok::Num("1"));
+        let nums: Vec<Tok> = stream.take(2).map(|t| t.value).collect();
+        assert!(nums == vec![Tok::Num("1"), Tok::Num("2")]);
+        assert!(r.pos() == 5);
+        struct WordLengths;
+        impl Lexer<'_, State, usize> for WordLengths {
+            fn lex_one(&mut self, r: &mut Rlex<'_, State, usize>) -> Option<usize> {
+                if r.char() == Some(' ') {
+                    r.next();
+                    return None;
+                }
+                Some(r.take_while(|c| c != ' ').len())
+            }
+        }
+        let mut r: Rlex<State, usize> = Rlex::new("a bcd  ef", State::Init);
+        let lens: Vec<usize> = r.lex(WordLengths).map(|t| t.value).collect();
+        assert!(lens == vec![1, 3, 2] && !r.has_errors());
+        let mut r: Rlex<State, char> = Rlex::new("xy", State::Init);
+        let mut started = false;
+        let toks: Vec<Spanned<char>> = r
+            .lex(|r: &mut Rlex<State, char>| {
+                if !std::mem::replace(&mut started, true) {
+                    return Some('^');
+                }
+                let c = r.char();
+                r.next();
+                c
+            })
+            .collect();
+        assert!(toks.iter().map(|t| t.value).collect::<String>() == "^xy");
+        assert!(toks[0].span == r.span_of(0, 0) && toks[1].span == r.span_of(0, 1));
+        assert!(!r.has_errors());
+        let mut r: Rlex<State, char> = Rlex::new("xy", State::Init);
+        let toks: Vec<Spanned<char>> = r.lex(|_: &mut Rlex<State, char>| Some('^')).collect();
+        assert!(toks.len() == 2 && toks[1].span == r.span_of(1, 1));
+        assert!(r.errors().len() == 2 && r.errors()[1].span == r.span_of(1, 2));
+    }
+
+    #[test]
+    fn test_lexer_builder() {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Mode {
+            Code,
+            Str,
+        }
+        #[derive(Debug, Clone, PartialEq)]
+        enum Tok<'a> {
+            Eq,
+            EqEq,
+            Ident(&'a str),
+            Num(&'a str),
+            Quote,
+            Text(&'a str),
+            If,
+        }
+        let lexer = LexerBuilder::new()
+            .rule(Mode::Code, Pattern::literal("="), Action::Emit(Tok::Eq))
+            .rule(Mode::Code, Pattern::literal("=="), Action::Emit(Tok::EqEq))
+            .rule(Mode::Code, Pattern::literal("if"), Action::Emit(Tok::If))
+            .rule(
+                Mode::Code,
+                Pattern::class(&['a'..='z', 'A'..='Z']),
+                Action::emit_with(Tok::Ident),
+            )
+            .rule(
+                Mode::Code,
+                Pattern::predicate(|c| c.is_ascii_digit()),
+                Action::emit_with(Tok::Num),
+            )
+            .rule(
+                Mode::Code,
+                Pattern::predicate(char::is_whitespace),
+                Action::Skip,
+            )
+            .rule(
+                Mode::Code,
+                Pattern::literal("\""),
+                Action::Then(vec![Action::Emit(Tok::Quote), Action::Push(Mode::Str)]),
+            )
+            .rule(
+                Mode::Code,
+                Pattern::literal("$"),
+                Action::error("stray `$`"),
+            )
+            .rule(
+                Mode::Str,
+                Pattern::closure(|rest| rest.find('"')),
+                Action::emit_with(Tok::Text),
+            )
+            .rule(
+                Mode::Str,
+                Pattern::literal("\""),
+                Action::Then(vec![Action::Emit(Tok::Quote), Action::Pop]),
+            )
+            .build();
+        let src = "if x == 10 $ y = \"hi there\" ifs ?";
+        let mut r: Rlex<Mode, Tok> = Rlex::new(src, Mode::Code);
+        let toks: Vec<Tok> = r.lex(lexer).map(|t| t.value).collect();
+        assert!(
+            toks == vec![
+                Tok::If,
+                Tok::Ident("x"),
+                Tok::EqEq,
+                Tok::Num("10"),
+                Tok::Ident("y"),
+                Tok::Eq,
+                Tok::Quote,
+                Tok::Text("hi there"),
+                Tok::Quote,
+                Tok::Ident("ifs"),
+            ]
+        );
+        assert!(r.state() == &Mode::Code);
+        assert!(r.errors().len() == 2);
+        assert!(r.errors()[0].message == "stray `$`" && r.errors()[0].span == r.span_of(11, 12));
+        assert!(r.errors()[1].message == "unexpected character");
+    }
+
+    #[test]
+    fn test_state_stack() {
+        let mut r: Rlex<State, Token> = Rlex::new("abc", State::Init);
+        r.trace_on();
+        assert!(r.state_pop().is_none() && r.state() == &State::Init);
+        r.state_push(State::Open);
+        r.state_push(State::Closed);
+        assert!(r.state_depth() == 2);
+        assert!(r.state_stack() == [State::Init, State::Open]);
+        let cp = r.checkpoint();
+        assert!(r.state_pop() == Some(State::Closed));
+        assert!(r.state_pop() == Some(State::Open));
+        assert!(r.state() == &State::Init && r.state_depth() == 0);
+        r.rollback(&cp);
+        assert!(r.state() == &State::Closed && r.state_depth() == 2);
+        r.trace_clear();
+        r.state_push(State::Init);
+        r.state_pop();
+        assert!(r.trace_emit() == "0:state_push(Init)\n1:state_pop() -> Some(Init)\n");
+    }
+
+    #[test]
+    fn test_state_hooks() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let source = "abcd".to_owned();
+        let mut r: Rlex<State, Token> = Rlex::new(&source, State::Closed);
+        let enter_log = log.clone();
+        r.on_enter(State::Open, move |t| {
+            enter_log
+                .borrow_mut()
+                .push(format!("enter {:?} from {:?}", t.to, t.from))
+        });
+        let exit_log = log.clone();
+        r.on_exit(State::Open, move |t| {
+            exit_log
+                .borrow_mut()
+                .push(format!("exit {:?} to {:?}", t.from, t.to))
+        });
+        r.state_validator_set(|t| match (t.from, t.to) {
+            (State::Closed, State::Open) if t.tokens_since == 0 => {
+                Err("cannot reopen without a token".to_owned())
+            }
+            _ => Ok(()),
+        });
+        let first = r.take_while(|c| c == 'a');
+        r.state_set(State::Open);
+        assert!(r.state() == &State::Closed);
+        assert!(r.errors().len() == 1 && r.errors()[0].span == r.span_of(1, 2));
+        assert!(r.errors()[0].message == "cannot reopen without a token");
+        r.token_push(Token::Tok1);
+        r.state_set(State::Open);
+        assert!(r.state() == &State::Open);
+        r.state_push(State::Init);
+        r.state_pop();
+        r.state_set(State::Closed);
+        r.state_push(State::Open);
+        assert!(r.state() == &State::Closed && r.state_depth() == 0 && r.errors().len() == 2);
+        drop(r);
+        assert!(first == "a");
+        assert!(
+            *log.borrow()
+                == vec![
+                    "enter Open from Closed",
+                    "exit Open to Init",
+                    "enter Open from Init",
+                    "exit Open to Closed",
+                ]
+        );
+        let lexer = LexerBuilder::new()
+            .rule(
+                State::Closed,
+                Pattern::literal("a"),
+                Action::Then(vec![Action::Emit(Token::Tok1), Action::Switch(State::Open)]),
+            )
+            .rule(
+                State::Open,
+                Pattern::literal("b"),
+                Action::Then(vec![Action::Emit(Token::Tok2), Action::Switch(State::Closed)]),
+            )
+            .build();
+        let mut r: Rlex<State, Token> = Rlex::new("abab", State::Closed);
+        r.state_validator_set(|t| match t.tokens_since {
+            0 => Err("no token since the last change".to_owned()),
+            _ => Ok(()),
+        });
+        let toks: Vec<Token> = r.lex(lexer).map(|t| t.value).collect();
+        assert!(toks == vec![Token::Tok1, Token::Tok2, Token::Tok1, Token::Tok2]);
+        assert!(!r.has_errors());
+        let mut r: Rlex<State, Token> = Rlex::new("ab", State::Closed);
+        r.state_validator_set(|t| match t.tokens_since {
+            0 => Err("no token since the last change".to_owned()),
+            _ => Ok(()),
+        });
+        let count = r
+            .lex(|r: &mut Rlex<State, Token>| {
+                r.next();
+                Some(Token::Tok1)
+            })
+            .count();
+        r.state_set(State::Open);
+        assert!(count == 2 && r.state() == &State::Open && !r.has_errors());
+        r.token_push(Token::Tok1);
+        r.token_push(Token::Tok2);
+        let cp = r.checkpoint();
+        r.state_set(State::Closed);
+        r.rollback(&cp);
+        r.state_set(State::Closed);
+        assert!(r.state() == &State::Closed && !r.has_errors());
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn test_regex() {
+        let re = Regex::new("[a-z_][a-z0-9_]*").unwrap();
+        assert!(re.match_len("foo_1 bar") == Some(5));
+        assert!(re.match_len("1foo").is_none());
+        let re = Regex::new("0x[0-9a-fA-F]+|\\d+(\\.\\d+)?").unwrap();
+        assert!(re.match_len("0x1F;") == Some(4));
+        assert!(re.match_len("3.14)") == Some(4));
+        assert!(re.match_len("3.x") == Some(1));
+        let re = Regex::new("\"([^\"\\\\]|\\\\.)*\"").unwrap();
+        assert!(re.match_len("\"a\\\"b\" c") == Some(6));
+        let re = Regex::new("a{2,3}b?").unwrap();
+        assert!(re.match_len("aaaab") == Some(3));
+        assert!(re.match_len("aab") == Some(3));
+        assert!(re.match_len("ab").is_none());
+        assert!(Regex::new("(a*)*b").unwrap().match_len("aab") == Some(3));
+        assert!(Regex::new("x*").unwrap().match_len("y") == Some(0));
+        assert!(Regex::new(".+").unwrap().match_len("ab\ncd") == Some(2));
+        assert!(Regex::new("[").is_err());
+        assert!(Regex::new("a{3,1}").is_err());
+        assert!(
+            Regex::new("*a").unwrap_err()
+                == RlexError::InvalidRegex("`*a`: `*` has nothing to repeat".to_owned())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn test_derive_rlex_token() {
+        #[derive(Debug, PartialEq, RlexToken)]
+        #[skip("[ \\t\\n]+")]
+        enum Tok<'a> {
+            #[token("let")]
+            Let,
+            #[token("=")]
+            Eq,
+            #[token("==")]
+            EqEq,
+            #[regex("[a-z_][a-z0-9_]*")]
+            Ident(&'a str),
+            #[regex(r"\d+")]
+            Num(String),
+            #[skip]
+            #[regex("//[^\\n]*")]
+            Comment,
+        }
+        let (toks, errors) = Tok::lex_all("let x = 10 // set x\nletter == y ?");
+        let values: Vec<&Tok> = toks.iter().map(|t| &t.value).collect();
+        assert!(
+            values
+                == vec![
+                    &Tok::Let,
+                    &Tok::Ident("x"),
+                    &Tok::Eq,
+                    &Tok::Num("10".to_owned()),
+                    &Tok::Ident("letter"),
+                    &Tok::EqEq,
+                    &Tok::Ident("y"),
+                ]
+        );
+        assert!(toks[3].span.range() == (8..10));
+        assert!(errors.len() == 1 && errors[0].span.range() == (32..33));
+        let mut r: Rlex<State, Tok> = Rlex::new("let", State::Init);
+        let toks: Vec<Spanned<Tok>> = r.lex(Tok::lex_token).collect();
+        assert!(toks.len() == 1 && toks[0].value == Tok::Let);
+        /// Tokens of a tiny language.
+        #[allow(dead_code)]
+        #[derive(Debug, PartialEq, RlexToken)]
+        #[repr(u8)]
+        enum Word {
+            /// The word `yes`.
+            #[token("yes")]
+            Yes,
+            #[allow(dead_code)]
+            #[token("no")]
+            No,
+        }
+        let (toks, errors) = Word::lex_all("yesno");
+        assert!(toks.len() == 2 && toks[1].value == Word::No && errors.is_empty());
+    }
+
     #[test]
     fn test_src() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
@@ -582,45 +2260,136 @@ mod tests {
         r.token_push(Token::Tok1);
         assert!(r.token_prev().unwrap() == &Token::Tok1);
         assert!(r.token_pop().unwrap() == Token::Tok1);
-        assert!(r.token_prev() == None);
+        assert!(r.token_prev().is_none());
         r.token_push(Token::Tok1);
         r.token_push(Token::Tok2);
         assert!(r.token_consume() == vec![Token::Tok1, Token::Tok2]);
     }
 
+    #[test]
+    fn test_token_spans() {
+        let mut r: Rlex<State, Token> = Rlex::new("aé cd", State::Init);
+        r.next();
+        r.token_push(Token::Tok1);
+        r.next();
+        r.next();
+        r.mark();
+        r.next();
+        r.token_push_span(Token::Tok2);
+        assert!(r.token_prev_span().unwrap().range() == (3..5));
+        let spans: Vec<Span> = r.toks_spanned().iter().map(|t| t.span).collect();
+        assert!(spans[0].byte_range() == (1..3));
+        assert!(r.src()[spans[1].byte_range()] == *"cd");
+        let toks = r.token_consume_spanned();
+        assert!(toks[0] == Spanned::new(Token::Tok1, spans[0]));
+        assert!(toks[1].value == Token::Tok2);
+        assert!(
+            toks[1].span
+                == Span {
+                    start: 3,
+                    end: 5,
+                    start_byte: 4,
+                    end_byte: 6
+                }
+        );
+    }
+
+    #[test]
+    fn test_byte_index() {
+        let src: String = (0..300)
+            .map(|i| if i % 3 == 0 { 'é' } else { 'a' })
+            .collect();
+        let r: Rlex<State, Token> = Rlex::new(&src, State::Init);
+        for pos in 0..=300 {
+            let expected = src.char_indices().nth(pos).map_or(src.len(), |(b, _)| b);
+            assert!(r.byte_of(pos) == expected);
+        }
+        let src: String = "é".repeat(BYTE_INDEX_STRIDE);
+        let r: Rlex<State, Token> = Rlex::new(&src, State::Init);
+        assert!(r.byte_of(BYTE_INDEX_STRIDE) == src.len());
+        assert!(r.str_from_rng(BYTE_INDEX_STRIDE - 1, BYTE_INDEX_STRIDE) == "é");
+    }
+
+    #[test]
+    fn test_borrowed_slices_outlive_lexer() {
+        let src = String::from("let x = ünï;");
+        let toks = {
+            let mut r: Rlex<State, &str> = Rlex::new(&src, State::Init);
+            while !r.at_end() {
+                r.mark();
+                r.next_until(' ');
+                r.prev();
+                let word = r.str_from_mark();
+                r.token_push_span(word);
+                r.next_by(2);
+            }
+            r.token_consume_spanned()
+        };
+        let words: Vec<&str> = toks.iter().map(|t| t.value).collect();
+        assert!(words == vec!["let", "x", "=", "ünï;"]);
+        assert!(&src[toks[3].span.byte_range()] == "ünï;");
+    }
+
     #[test]
     fn test_rlex_next_and_prev() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
-        assert_eq!(r.char(), 'a');
+        assert_eq!(r.char(), Some('a'));
         r.next();
-        assert_eq!(r.char(), 'b');
+        assert_eq!(r.char(), Some('b'));
         r.next();
-        assert_eq!(r.char(), 'c');
+        assert_eq!(r.char(), Some('c'));
         r.next();
-        assert_eq!(r.char(), 'd');
+        assert_eq!(r.char(), Some('d'));
         r.next();
-        assert_eq!(r.char(), 'd');
+        assert_eq!(r.char(), None);
         r.next();
-        assert_eq!(r.char(), 'd');
+        assert_eq!(r.char(), None);
+        r.prev();
+        assert_eq!(r.char(), Some('d'));
         r.prev();
-        assert_eq!(r.char(), 'c');
+        assert_eq!(r.char(), Some('c'));
         r.prev();
-        assert_eq!(r.char(), 'b');
+        assert_eq!(r.char(), Some('b'));
         r.prev();
-        assert_eq!(r.char(), 'a');
+        assert_eq!(r.char(), Some('a'));
         r.prev();
-        assert_eq!(r.char(), 'a');
+        assert_eq!(r.char(), Some('a'));
+    }
+
+    #[test]
+    fn test_rlex_empty_source() {
+        let mut r: Rlex<State, Token> = Rlex::new("", State::Init);
+        assert!(r.at_start());
+        assert!(r.at_end());
+        assert_eq!(r.char(), None);
+        assert_eq!(r.peek(), None);
+        assert_eq!(r.peek_back(), None);
+        r.next();
         r.prev();
-        assert_eq!(r.char(), 'a');
+        r.collect();
+        assert!(r.str_from_start() == "");
+        assert!(r.str_from_end() == "");
+        assert!(r.str_from_mark() == "");
+        assert!(r.str_from_rng(0, 3).is_empty());
+        assert!(r.str_from_collection() == "");
+        assert!(!r.is_in_quote());
+        let r: Result<Rlex<State, Token>, RlexError> = Rlex::try_new("", State::Init);
+        assert_eq!(r.unwrap_err(), RlexError::EmptySource);
+        let r: Result<Rlex<State, Token>, RlexError> = Rlex::try_new("a", State::Init);
+        assert!(r.is_ok());
     }
 
     #[test]
     fn test_rlex_at_start_and_at_end() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
+        let mut seen = String::new();
         while !r.at_end() {
+            seen.push(r.char().unwrap());
             r.next();
         }
         assert!(r.at_end());
+        assert!(seen == "abcd");
+        assert!(r.pos() == 4);
         while !r.at_start() {
             r.prev();
         }
@@ -631,56 +2400,61 @@ mod tests {
     fn test_rlex_next_by() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
         r.next_by(0);
-        assert!(r.char() == 'a');
+        assert!(r.char() == Some('a'));
         r.next_by(1);
-        assert!(r.char() == 'b');
+        assert!(r.char() == Some('b'));
         r.goto_start();
         r.next_by(2);
-        assert!(r.char() == 'c');
+        assert!(r.char() == Some('c'));
         r.goto_start();
         r.next_by(3);
-        assert!(r.char() == 'd');
+        assert!(r.char() == Some('d'));
         r.goto_start();
         r.next_by(4);
-        assert!(r.char() == 'd');
+        assert!(r.char().is_none());
+        r.goto_start();
+        r.next_by(9);
+        assert!(r.pos() == 4);
     }
 
     #[test]
     fn test_rlex_peek() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
-        assert!(r.peek() == 'b');
+        assert!(r.peek() == Some('b'));
+        r.goto_pos(3);
+        assert!(r.peek().is_none());
         r.goto_end();
-        assert!(r.peek() == 'd');
+        assert!(r.peek().is_none());
     }
 
     #[test]
     fn test_rlex_peek_by() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
-        assert!(r.peek_by(0) == 'a');
-        assert!(r.peek_by(1) == 'b');
-        assert!(r.peek_by(2) == 'c');
-        assert!(r.peek_by(3) == 'd');
-        assert!(r.peek_by(4) == 'd');
+        assert!(r.peek_by(0) == Some('a'));
+        assert!(r.peek_by(1) == Some('b'));
+        assert!(r.peek_by(2) == Some('c'));
+        assert!(r.peek_by(3) == Some('d'));
+        assert!(r.peek_by(4).is_none());
     }
 
     #[test]
     fn test_rlex_peek_back() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
         r.goto_end();
-        assert!(r.peek_back() == 'c');
+        assert!(r.peek_back() == Some('d'));
         r.goto_start();
-        assert!(r.peek_back() == 'a');
+        assert!(r.peek_back() == Some('a'));
     }
 
     #[test]
     fn test_rlex_peek_back_by() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
-        r.goto_end();
-        assert!(r.peek_back_by(0) == 'd');
-        assert!(r.peek_back_by(1) == 'c');
-        assert!(r.peek_back_by(2) == 'b');
-        assert!(r.peek_back_by(3) == 'a');
-        assert!(r.peek_back_by(4) == 'a');
+        r.goto_pos(3);
+        assert!(r.peek_back_by(0) == Some('d'));
+        assert!(r.peek_back_by(1) == Some('c'));
+        assert!(r.peek_back_by(2) == Some('b'));
+        assert!(r.peek_back_by(3) == Some('a'));
+        assert!(r.peek_back_by(4) == Some('a'));
     }
 
     #[test]
@@ -690,10 +2464,14 @@ mod tests {
         assert!(r.str_from_start() == "ab");
         r.goto_end();
         assert!(r.str_from_start() == "abcd");
+        assert!(r.str_from_end() == "");
+        r.prev();
         r.prev();
         r.mark();
         r.next();
         assert!(r.str_from_mark() == "cd");
+        r.next();
+        assert!(r.str_from_mark() == "cd");
         r.goto_start();
         assert!(r.str_from_end() == "abcd");
         r.next();
@@ -708,17 +2486,19 @@ mod tests {
         assert!(r.str_from_rng(0, 3) == "abcd");
         assert!(r.str_from_rng(0, 22) == "abcd");
         assert!(r.str_from_rng(22, 0) == "abcd");
+        let r: Rlex<State, Token> = Rlex::new("héllo", State::Init);
+        assert!(r.str_from_rng(1, 2) == "él");
     }
 
     #[test]
     fn test_rlex_is_in_quote() {
         let mut r: Rlex<State, Token> = Rlex::new("\"Hello, I am Quoted!\"", State::Init);
-        while !r.at_end() {
+        while r.pos() < 20 {
             assert!(r.is_in_quote());
             r.next();
         }
         assert!(!r.is_in_quote());
-        assert!(r.char() == '"');
+        assert!(r.char() == Some('"'));
         let mut r: Rlex<State, Token> = Rlex::new("Hello, I am not Quoted!", State::Init);
         while !r.at_end() {
             assert!(!r.is_in_quote());
@@ -731,6 +2511,123 @@ mod tests {
         assert!(!r.is_in_quote());
     }
 
+    #[test]
+    fn test_rlex_quote_config() {
+        let mut r: Rlex<State, Token> = Rlex::new("\"it's\" x", State::Init);
+        r.next_until('\'');
+        assert!(r.is_in_quote());
+        r.next_until('x');
+        assert!(!r.is_in_quote());
+
+        let mut r: Rlex<State, Token> = Rlex::new("'it''s' x", State::Init);
+        r.quote_config_set(QuoteConfig::sql());
+        r.next_until('s');
+        assert!(r.is_in_quote());
+        r.next();
+        assert!(!r.is_in_quote());
+        r.prev_until('t');
+        r.next();
+        assert!(r.is_in_quote());
+        r.next();
+        assert!(r.is_in_quote());
+
+        let mut r: Rlex<State, Token> = Rlex::new("`a \\` b` c", State::Init);
+        r.next_until('b');
+        assert!(!r.is_in_quote());
+        r.quote_config_set(QuoteConfig::default().with_backticks());
+        assert!(r.is_in_quote());
+        r.next_until('c');
+        assert!(!r.is_in_quote());
+
+        let mut r: Rlex<State, Token> = Rlex::new("«a «b» c» d", State::Init);
+        r.quote_config_set(QuoteConfig::new().with_pair('«', '»').with_nesting(true));
+        r.next_until('c');
+        assert!(r.is_in_quote());
+        r.next_until('d');
+        assert!(!r.is_in_quote());
+        r.quote_config_set(QuoteConfig::new().with_pair('«', '»'));
+        r.goto_pos(8);
+        assert!(!r.is_in_quote());
+    }
+
+    #[test]
+    fn test_rlex_depth_of() {
+        let mut r: Rlex<State, Token> = Rlex::new("f(a[{b}], \"(\") x", State::Init);
+        let mut depths = vec![];
+        while !r.at_end() {
+            depths.push((r.depth_of('('), r.depth_of(']'), r.depth_of('{')));
+            r.next();
+        }
+        assert!(depths[0] == (0, 0, 0));
+        assert!(depths[1] == (1, 0, 0));
+        assert!(depths[4] == (1, 1, 1));
+        assert!(depths[5] == (1, 1, 1));
+        assert!(depths[6] == (1, 1, 0));
+        assert!(depths[7] == (1, 0, 0));
+        assert!(depths[11] == (1, 0, 0));
+        assert!(depths[13] == (0, 0, 0));
+        r.goto_pos(4);
+        assert!(r.depth_of('{') == 1);
+        assert!(r.depth_of('x') == 0);
+        r.goto_pos(10);
+        assert!(r.is_in_quote());
+        r.quote_config_set(QuoteConfig::new());
+        assert!(!r.is_in_quote());
+        r.next();
+        assert!(r.depth_of('(') == 2);
+    }
+
+    #[test]
+    fn test_rlex_is_in_comment() {
+        let src = "a // \"b\nc /* d \"*/ \"/*\" # e";
+        let mut r: Rlex<State, Token> = Rlex::new(src, State::Init);
+        assert!(!r.is_in_comment());
+        r.comment_config_set(CommentConfig::c());
+        let flags: String = src
+            .chars()
+            .enumerate()
+            .map(|(i, _)| {
+                r.goto_pos(i);
+                match (r.is_in_comment(), r.is_in_quote()) {
+                    (true, _) => 'c',
+                    (false, true) => 'q',
+                    (false, false) => '.',
+                }
+            })
+            .collect();
+        assert!(flags == "..ccccc...cccccccc.qqq.....");
+        r.goto_pos(2);
+        r.skip_comments_and_whitespace();
+        assert!(r.char() == Some('c'));
+        r.next();
+        r.skip_comments_and_whitespace();
+        assert!(r.char() == Some('"'));
+
+        let mut r: Rlex<State, Token> = Rlex::new("/* a /* b */ c */ d", State::Init);
+        r.comment_config_set(CommentConfig::rust());
+        r.next_until('c');
+        assert!(r.is_in_comment());
+        r.next_until('d');
+        assert!(!r.is_in_comment());
+        r.comment_config_set(CommentConfig::c());
+        r.prev_until('c');
+        assert!(!r.is_in_comment());
+
+        let mut r: Rlex<State, Token> = Rlex::new("x <!-- y --> -- z\n# w", State::Init);
+        r.comment_config_set(CommentConfig::html().with_line("#"));
+        r.next_until('y');
+        assert!(r.is_in_comment());
+        r.next_until('z');
+        assert!(!r.is_in_comment());
+        r.comment_config_set(CommentConfig::sql().with_line("#"));
+        assert!(r.is_in_comment());
+        r.goto_end();
+        assert!(r.is_in_comment());
+        r.goto_start();
+        r.skip_comments_and_whitespace();
+        assert!(r.char() == Some('x'));
+    }
+
     #[test]
     fn test_rlex_next_until_and_prev_until() {
         let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
@@ -739,6 +2636,61 @@ mod tests {
         r.next();
         r.prev_until('b');
         assert!(r.pos() == 1);
+        r.next_until('z');
+        assert!(r.at_end());
+        r.prev_until('z');
+        assert!(r.at_start());
+    }
+
+    #[test]
+    fn test_rlex_predicate_navigation() {
+        let mut r: Rlex<State, Token> = Rlex::new("1234 abc;def", State::Init);
+        assert!(r.take_while(|c| c.is_ascii_digit()) == "1234");
+        assert!(r.char() == Some(' '));
+        assert!(r.take_while(|c| c.is_ascii_digit()).is_empty());
+        r.next_until_fn(|c| c.is_whitespace() || c == ';');
+        assert!(r.pos() == 4);
+        r.next();
+        r.next_until_fn(|c| c.is_whitespace() || c == ';');
+        assert!(r.char() == Some(';'));
+        r.prev();
+        r.prev_while(|c| c.is_alphabetic());
+        assert!(r.char() == Some(' '));
+        r.next_while(|c| c != 'f');
+        assert!(r.char() == Some('f'));
+        r.next_while(|_| true);
+        assert!(r.at_end());
+        r.goto_pos(3);
+        r.prev_while(|c| c.is_ascii_digit());
+        assert!(r.at_start());
+        r.trace_on();
+        r.next_while(|c| c.is_ascii_digit());
+        assert!(r.trace_emit().ends_with(":next_while(fn)\n"));
+    }
+
+    #[test]
+    fn test_rlex_literal_matching() {
+        let mut r: Rlex<State, Token> = Rlex::new("<!-- x --> === SELECT", State::Init);
+        assert!(r.starts_with_at_cursor("<!--"));
+        assert!(!r.starts_with_at_cursor("<!---"));
+        assert!(!r.eat("<?"));
+        assert!(r.pos() == 0);
+        assert!(r.eat("<!--"));
+        assert!(r.char() == Some(' '));
+        r.next_until('-');
+        assert!(r.eat_any(&["-", "--", "-->", ""]) == Some("-->"));
+        r.next();
+        assert!(r.eat_any(&["=", "==", "===", "!="]) == Some("==="));
+        assert!(r.eat_any(&["=", "=="]).is_none());
+        r.next();
+        assert!(!r.starts_with_at_cursor("select"));
+        assert!(r.starts_with_at_cursor_ignore_case("select"));
+        assert!(r.eat_any_ignore_case(&["sel", "select", "from"]) == Some("SELECT"));
+        assert!(r.at_end());
+        r.goto_pos(15);
+        assert!(r.eat_ignore_case("sElEcT"));
+        assert!(!r.eat_ignore_case("x"));
+        assert!(r.eat(""));
     }
 
     #[test]
@@ -749,14 +2701,167 @@ mod tests {
         assert!(r.next_by_is('b', 1));
         assert!(r.next_by_is('c', 2));
         assert!(r.next_by_is('d', 3));
-        assert!(r.next_by_is('d', 4));
-        r.goto_end();
+        assert!(!r.next_by_is('d', 4));
+        r.goto_pos(3);
+        assert!(!r.next_is('d'));
         assert!(r.prev_is('c'));
         assert!(r.prev_by_is('d', 0));
         assert!(r.prev_by_is('c', 1));
         assert!(r.prev_by_is('b', 2));
         assert!(r.prev_by_is('a', 3));
-        assert!(r.prev_by_is('a', 4));
+        assert!(!r.prev_by_is('a', 4));
+        r.goto_start();
+        assert!(!r.prev_is('a'));
+        assert!(r.prev_by_is('a', 0));
+        r.goto_end();
+        assert!(r.prev_is('d'));
+        assert!(!r.next_by_is('d', 0));
+    }
+
+    #[test]
+    fn test_rlex_peek_opt() {
+        let mut r: Rlex<State, Token> = Rlex::new("abcd", State::Init);
+        assert!(r.peek_opt() == Some('b'));
+        assert!(r.peek_by_opt(0) == Some('a'));
+        assert!(r.peek_by_opt(3) == Some('d'));
+        assert!(r.peek_by_opt(4).is_none());
+        assert!(r.peek_back_opt().is_none());
+        assert!(r.peek_back_by_opt(1).is_none());
+        r.goto_pos(3);
+        assert!(r.peek_opt().is_none());
+        assert!(r.peek_back_opt() == Some('c'));
+        assert!(r.peek_back_by_opt(3) == Some('a'));
+        assert!(r.peek_back_by_opt(4).is_none());
+        assert!(r.pos() == 3);
+    }
+
+    #[test]
+    fn test_rlex_line_and_col() {
+        let mut r: Rlex<State, Token> = Rlex::new("ab\ncd\r\nef\rg", State::Init);
+        assert!(r.line() == 1 && r.col() == 1);
+        r.next_until('c');
+        assert!(r.line() == 2 && r.col() == 1);
+        r.next_until('\n');
+        assert!(r.line() == 2 && r.col() == 4);
+        r.next();
+        assert!(r.line() == 3 && r.col() == 1);
+        r.next_until('g');
+        assert!(r.line() == 4 && r.col() == 1);
+        r.goto_end();
+        assert!(r.line() == 4 && r.col() == 2);
+        r.prev_until('\r');
+        assert!(r.line() == 3 && r.col() == 3);
+        r.prev_until('d');
+        r.prev();
+        assert!(r.line() == 2 && r.col() == 1);
+        r.prev();
+        assert!(r.line() == 1 && r.col() == 3);
+        r.goto_pos(9);
+        assert!(r.line() == 3 && r.col() == 3);
+        r.goto_pos(1);
+        assert!(r.line() == 1 && r.col() == 2);
+        assert!(r.line_col_of(4) == (2, 2));
+        assert!(r.line_col_of(12) == (4, 2));
+        assert!(r.line_col_of(0) == (1, 1));
+    }
+
+    #[test]
+    fn test_rlex_mark_stack_and_named_marks() {
+        let mut r: Rlex<State, Token> = Rlex::new("<a href=\"x\">\nhi", State::Init);
+        r.mark();
+        r.mark_as("tag");
+        r.next_until('h');
+        r.mark_push();
+        r.mark_as("attr");
+        r.next_until('"');
+        r.mark_push();
+        r.next();
+        assert!(r.str_from_mark_top() == Some("\"x"));
+        assert!(r.mark_pop() == Some(8));
+        assert!(r.str_from_mark_top() == Some("href=\"x"));
+        assert!(r.mark_pop() == Some(3));
+        assert!(r.str_from_mark_top().is_none());
+        assert!(r.str_from_mark() == "<a href=\"x");
+        r.next_until('i');
+        assert!(r.str_from_named("attr") == Some("href=\"x\">\nhi"));
+        assert!(r.line() == 2);
+        r.goto_named("attr").unwrap();
+        assert!(r.pos() == 3 && r.line() == 1 && r.col() == 4);
+        r.goto_named("tag").unwrap();
+        assert!(r.at_start());
+        assert!(r.goto_named("nope").err() == Some(RlexError::UnknownMark("nope".to_owned())));
+        assert!(r.str_from_named("nope").is_none());
+    }
+
+    #[test]
+    fn test_rlex_checkpoint_and_rollback() {
+        let mut r: Rlex<State, Token> = Rlex::new("a <b> c", State::Init);
+        r.token_push(Token::Tok1);
+        r.collect();
+        r.next_by(2);
+        r.trace_on();
+        let cp = r.checkpoint();
+        r.state_set(State::Open);
+        r.mark();
+        r.collect();
+        r.collect_pop();
+        r.collect_pop();
+        r.token_push(Token::Tok2);
+        r.next_until('c');
+        r.rollback(&cp);
+        assert!(r.pos() == 2);
+        assert!(r.state() == &State::Init);
+        assert!(r.str_from_collection() == "a");
+        assert!(r.toks() == &vec![Token::Tok1]);
+        assert!(r.str_from_mark() == "a <");
+        assert!(r.trace_emit().starts_with("0:checkpoint()\n1:rollback()\n"));
+        assert!(r.trace_events()[1].pos_before == 6 && r.trace_events()[1].pos_after == 2);
+        assert!(r.trace_events()[1].state == "Init");
+        r.trace_off();
+
+        let generic = r.try_lex(|r| {
+            r.next();
+            r.next_until('>');
+            if r.char() == Some('>') {
+                r.token_push(Token::Tok3);
+                Some(r.pos())
+            } else {
+                None
+            }
+        });
+        assert!(generic == Some(4));
+        let missing: Result<(), &str> = r.try_lex(|r| {
+            r.state_set(State::Closed);
+            r.next_until('<');
+            if r.at_end() {
+                return Err("no <");
+            }
+            Ok(())
+        });
+        assert!(missing.is_err());
+        assert!(r.pos() == 4);
+        assert!(r.state() == &State::Init);
+        let popped = r.try_lex(|r| {
+            r.token_pop();
+            r.token_pop();
+            r.token_push(Token::Tok2);
+            r.token_pop();
+            false
+        });
+        assert!(!popped);
+        r.commit(&cp);
+        assert!(r.try_lex(|r| r.token_pop()) == Some(Token::Tok3));
+        assert!(r.popped_tokens.is_empty() && r.token_clone.is_none());
+        r.token_push(Token::Tok3);
+        let outer = r.checkpoint();
+        assert!(r.try_lex(|r| r.token_pop()).is_some());
+        assert!(r.popped_tokens.len() == 1);
+        r.rollback(&outer);
+        r.token_pop();
+        r.rollback(&outer);
+        r.commit(&outer);
+        assert!(r.popped_tokens.is_empty());
+        assert!(r.token_consume() == vec![Token::Tok1, Token::Tok3]);
     }
 
     #[test]
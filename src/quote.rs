@@ -0,0 +1,141 @@
+/// Describes which characters open and close quotes, and how quotes are escaped.
+///
+/// While a quote is open, only its own closing delimiter ends it. Other delimiters are
+/// literal unless `nested` is set, in which case they open a quote within the quote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuoteConfig {
+    /// Opening and closing delimiter pairs, such as `('"', '"')`.
+    pub pairs: Vec<(char, char)>,
+    /// A character that makes the following character literal, such as `\`.
+    pub escape: Option<char>,
+    /// Whether a doubled closing delimiter inside a quote is literal, as in SQL's `''`.
+    pub doubled_escape: bool,
+    /// Whether delimiters inside a quote open a nested quote.
+    pub nested: bool,
+}
+
+impl Default for QuoteConfig {
+    /// Double and single quotes, escaped with a backslash.
+    fn default() -> QuoteConfig {
+        QuoteConfig {
+            pairs: vec![('"', '"'), ('\'', '\'')],
+            escape: Some('\\'),
+            doubled_escape: false,
+            nested: false,
+        }
+    }
+}
+
+impl QuoteConfig {
+    /// Creates a config with no delimiters and no escape character.
+    pub fn new() -> QuoteConfig {
+        QuoteConfig {
+            pairs: vec![],
+            escape: None,
+            doubled_escape: false,
+            nested: false,
+        }
+    }
+
+    /// SQL quoting: single and double quotes, escaped by doubling the delimiter.
+    pub fn sql() -> QuoteConfig {
+        QuoteConfig {
+            pairs: vec![('\'', '\''), ('"', '"')],
+            escape: None,
+            doubled_escape: true,
+            nested: false,
+        }
+    }
+
+    /// Adds a delimiter pair.
+    pub fn with_pair(mut self, open: char, close: char) -> QuoteConfig {
+        self.pairs.push((open, close));
+        self
+    }
+
+    /// Adds backticks as a delimiter pair.
+    pub fn with_backticks(self) -> QuoteConfig {
+        self.with_pair('`', '`')
+    }
+
+    /// Sets the escape character.
+    pub fn with_escape(mut self, escape: Option<char>) -> QuoteConfig {
+        self.escape = escape;
+        self
+    }
+
+    /// Sets whether a doubled closing delimiter is literal.
+    pub fn with_doubled_escape(mut self, doubled_escape: bool) -> QuoteConfig {
+        self.doubled_escape = doubled_escape;
+        self
+    }
+
+    /// Sets whether quotes nest.
+    pub fn with_nesting(mut self, nested: bool) -> QuoteConfig {
+        self.nested = nested;
+        self
+    }
+
+    /// Returns the closing delimiter for `open`, if it opens a quote.
+    fn closer_for(&self, open: char) -> Option<char> {
+        self.pairs
+            .iter()
+            .find(|(o, _)| *o == open)
+            .map(|(_, close)| *close)
+    }
+}
+
+/// Quote state built up one character at a time.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct QuoteScan {
+    /// Closing delimiters of the open quotes, innermost last.
+    closers: Vec<char>,
+    escaped: bool,
+    skip: bool,
+}
+
+impl QuoteScan {
+    /// Returns `true` if a quote is open.
+    pub(crate) fn in_quote(&self) -> bool {
+        !self.closers.is_empty()
+    }
+
+    /// Returns `true` if the next character will be taken literally, because it follows
+    /// an escape or completes a doubled delimiter.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.escaped || self.skip
+    }
+
+    /// Feeds `c`, with `next` being the character after it.
+    pub(crate) fn step(&mut self, config: &QuoteConfig, c: char, next: Option<char>) {
+        if self.skip {
+            self.skip = false;
+            return;
+        }
+        if self.escaped {
+            self.escaped = false;
+            return;
+        }
+        if config.escape == Some(c) {
+            self.escaped = true;
+            return;
+        }
+        let Some(&close) = self.closers.last() else {
+            if let Some(close) = config.closer_for(c) {
+                self.closers.push(close);
+            }
+            return;
+        };
+        if c == close {
+            if config.doubled_escape && next == Some(c) {
+                self.skip = true;
+            } else {
+                self.closers.pop();
+            }
+        } else if config.nested {
+            if let Some(close) = config.closer_for(c) {
+                self.closers.push(close);
+            }
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use crate::RlexError;
+
+/// A small regular expression, matched at the start of a string with longest-match
+/// semantics. Used by `#[regex]` in `#[derive(RlexToken)]`.
+///
+/// The supported syntax is literal chars, `.` (any char but `\n`), classes such as
+/// `[a-z_]` and `[^"]`, groups, alternation with `|`, and the greedy quantifiers `*`,
+/// `+`, `?`, `{n}`, `{n,}` and `{n,m}`. The escapes `\d`, `\w` and `\s` (and their
+/// negations) are ASCII-only, `\n`, `\r` and `\t` match those chars, and any other
+/// escaped char matches itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Regex {
+    inner: rlex_regex::Regex,
+}
+
+impl Regex {
+    /// Compiles a pattern.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RlexError::InvalidRegex`] if the pattern is malformed or uses syntax
+    /// outside the supported subset.
+    pub fn new(pattern: &str) -> Result<Regex, RlexError> {
+        let inner = rlex_regex::Regex::new(pattern).map_err(RlexError::InvalidRegex)?;
+        Ok(Regex { inner })
+    }
+
+    /// Returns the pattern the regex was compiled from.
+    pub fn as_str(&self) -> &str {
+        self.inner.as_str()
+    }
+
+    /// Returns the byte length of the longest match at the start of `text`, or `None`
+    /// if the regex does not match there. A pattern that can match nothing may return
+    /// `Some(0)`.
+    pub fn match_len(&self, text: &str) -> Option<usize> {
+        self.inner.match_len(text)
+    }
+}
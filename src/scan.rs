@@ -0,0 +1,153 @@
+use crate::comment::CommentConfig;
+use crate::quote::{QuoteConfig, QuoteScan};
+
+/// Bracket pairs tracked by [`Scanner`], indexed the same as its depth counters.
+const BRACKETS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+/// Returns the index into [`BRACKETS`] for an opening or closing bracket.
+pub(crate) fn bracket_index(c: char) -> Option<usize> {
+    BRACKETS
+        .iter()
+        .position(|(open, close)| *open == c || *close == c)
+}
+
+/// Quote, comment and bracket state for a prefix of the source, extended one char at a
+/// time as queries move forward and rebuilt from the start only when they move backward.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Scanner {
+    quote: QuoteScan,
+    depths: [usize; 3],
+    line_comment: bool,
+    /// Index into [`CommentConfig::block`] of the open block comment.
+    block_comment: Option<usize>,
+    block_depth: usize,
+    /// Remaining chars of a comment delimiter that has already been matched.
+    delimiter_left: usize,
+    /// Whether the last scanned char belongs to a comment, delimiters included.
+    last_in_comment: bool,
+    /// Number of chars scanned so far.
+    upto: usize,
+    /// Byte offset of the next char to scan.
+    byte: usize,
+}
+
+/// The quote and comment rules a [`Scanner`] follows.
+pub(crate) struct ScanRules<'r> {
+    pub(crate) quotes: &'r QuoteConfig,
+    pub(crate) comments: &'r CommentConfig,
+}
+
+impl Scanner {
+    /// Scans the source until the first `count` chars have been seen.
+    pub(crate) fn advance_to(&mut self, source: &str, rules: &ScanRules, count: usize) {
+        if count < self.upto {
+            *self = Scanner::default();
+        }
+        while self.upto < count {
+            let rest = &source[self.byte..];
+            let Some(c) = rest.chars().next() else {
+                break;
+            };
+            self.step(rules, c, rest);
+            self.upto += 1;
+            self.byte += c.len_utf8();
+        }
+    }
+
+    /// Feeds `c`, where `rest` is the source starting at `c`.
+    fn step(&mut self, rules: &ScanRules, c: char, rest: &str) {
+        if self.delimiter_left > 0 {
+            self.delimiter_left -= 1;
+            self.last_in_comment = true;
+            return;
+        }
+        if self.line_comment {
+            if c == '\n' || c == '\r' {
+                self.line_comment = false;
+                self.last_in_comment = false;
+            } else {
+                self.last_in_comment = true;
+            }
+            return;
+        }
+        if let Some(i) = self.block_comment {
+            let (open, close) = &rules.comments.block[i];
+            if rest.starts_with(close.as_str()) {
+                self.block_depth -= 1;
+                if self.block_depth == 0 {
+                    self.block_comment = None;
+                }
+                self.start_delimiter(close);
+            } else if rules.comments.nested && rest.starts_with(open.as_str()) {
+                self.block_depth += 1;
+                self.start_delimiter(open);
+            }
+            self.last_in_comment = true;
+            return;
+        }
+        self.last_in_comment = false;
+        let in_code = !self.quote.in_quote() && !self.quote.is_pending();
+        if in_code && self.try_open_comment(rules.comments, rest) {
+            self.last_in_comment = true;
+            return;
+        }
+        let next = rest[c.len_utf8()..].chars().next();
+        self.quote.step(rules.quotes, c, next);
+        if in_code && !self.quote.in_quote() && !self.quote.is_pending() {
+            self.step_bracket(c);
+        }
+    }
+
+    /// Opens a comment if `rest` starts with a comment delimiter.
+    fn try_open_comment(&mut self, comments: &CommentConfig, rest: &str) -> bool {
+        let starts = |delim: &String| !delim.is_empty() && rest.starts_with(delim.as_str());
+        if let Some(start) = comments.line.iter().find(|start| starts(start)) {
+            self.line_comment = true;
+            self.start_delimiter(start);
+            return true;
+        }
+        if let Some(i) = comments.block.iter().position(|(open, _)| starts(open)) {
+            self.block_comment = Some(i);
+            self.block_depth = 1;
+            self.start_delimiter(&comments.block[i].0);
+            return true;
+        }
+        false
+    }
+
+    /// Marks the rest of a matched delimiter, after its first char, as comment.
+    fn start_delimiter(&mut self, delim: &str) {
+        self.delimiter_left = delim.chars().count().saturating_sub(1);
+    }
+
+    fn step_bracket(&mut self, c: char) {
+        let Some(i) = bracket_index(c) else {
+            return;
+        };
+        if BRACKETS[i].0 == c {
+            self.depths[i] += 1;
+        } else {
+            self.depths[i] = self.depths[i].saturating_sub(1);
+        }
+    }
+
+    /// Returns `true` if a quote is open after the scanned chars.
+    pub(crate) fn in_quote(&self) -> bool {
+        self.quote.in_quote()
+    }
+
+    /// Returns `true` if the last scanned char is part of a comment.
+    pub(crate) fn last_in_comment(&self) -> bool {
+        self.last_in_comment
+    }
+
+    /// Returns `true` if a comment is still open after the scanned chars.
+    pub(crate) fn comment_open(&self) -> bool {
+        self.line_comment || self.block_comment.is_some() || self.delimiter_left > 0
+    }
+
+    /// Returns the nesting depth of the bracket pair at `i` in [`BRACKETS`].
+    pub(crate) fn depth(&self, i: usize) -> usize {
+        self.depths[i]
+    }
+}
@@ -0,0 +1,138 @@
+use crate::breaks_line;
+
+/// One line of the source, used when rendering carets and underlines under it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SourceLine<'a> {
+    /// The 1-based line number.
+    pub(crate) number: usize,
+    /// The char position of the first char on the line.
+    pub(crate) start: usize,
+    /// The byte offset of the first char on the line.
+    pub(crate) start_byte: usize,
+    /// The line's text, without its line break.
+    pub(crate) text: &'a str,
+    /// The number of chars in the line's break: 2 for `\r\n`, 1 for `\n` or `\r`, and
+    /// 0 on the last line.
+    pub(crate) break_len: usize,
+}
+
+impl<'a> SourceLine<'a> {
+    /// Returns the number of chars on the line, not counting its line break.
+    pub(crate) fn len(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    /// Returns the line's text with tabs widened to a single space, so that caret
+    /// columns line up with the chars above them.
+    pub(crate) fn display(&self) -> String {
+        self.text.replace('\t', " ")
+    }
+}
+
+/// Returns the line containing the char at `pos`. A line break belongs to the line it
+/// ends.
+pub(crate) fn line_at(source: &str, pos: usize) -> SourceLine<'_> {
+    let mut number = 1;
+    let mut start = 0;
+    let mut start_byte = 0;
+    let mut chars = source.char_indices().enumerate().peekable();
+    while let Some((i, (byte, c))) = chars.next() {
+        if i == pos {
+            break;
+        }
+        let next = chars.peek().map(|(_, (_, c))| *c);
+        if breaks_line(Some(c), next) {
+            number += 1;
+            start = i + 1;
+            start_byte = byte + c.len_utf8();
+        }
+    }
+    let rest = &source[start_byte..];
+    let end = rest.find(['\n', '\r']).unwrap_or(rest.len());
+    SourceLine {
+        number,
+        start,
+        start_byte,
+        text: &rest[..end],
+        break_len: break_len(&rest[end..]),
+    }
+}
+
+/// The start of every line in a source, for finding the line containing a position
+/// without rescanning the source each time.
+#[derive(Debug, Clone)]
+pub(crate) struct LineIndex<'a> {
+    source: &'a str,
+    /// The char position and byte offset of the first char on each line.
+    starts: Vec<(usize, usize)>,
+}
+
+impl<'a> LineIndex<'a> {
+    /// Scans `source` once, recording where each line starts.
+    pub(crate) fn new(source: &'a str) -> LineIndex<'a> {
+        let mut starts = vec![(0, 0)];
+        let mut chars = source.char_indices().enumerate().peekable();
+        while let Some((i, (byte, c))) = chars.next() {
+            let next = chars.peek().map(|(_, (_, c))| *c);
+            if breaks_line(Some(c), next) {
+                starts.push((i + 1, byte + c.len_utf8()));
+            }
+        }
+        LineIndex { source, starts }
+    }
+
+    /// Returns the line containing the char at `pos`, as [`line_at`] does.
+    pub(crate) fn line_at(&self, pos: usize) -> SourceLine<'a> {
+        let index = self.starts.partition_point(|(start, _)| *start <= pos) - 1;
+        let (start, start_byte) = self.starts[index];
+        let rest = &self.source[start_byte..];
+        let end = rest.find(['\n', '\r']).unwrap_or(rest.len());
+        SourceLine {
+            number: index + 1,
+            start,
+            start_byte,
+            text: &rest[..end],
+            break_len: break_len(&rest[end..]),
+        }
+    }
+}
+
+/// Returns the number of chars in the line break at the start of `rest`, if any.
+fn break_len(rest: &str) -> usize {
+    if rest.starts_with("\r\n") {
+        2
+    } else if rest.starts_with(['\n', '\r']) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Returns every line that overlaps the chars `start..end`, counting each line's break
+/// as part of it. An empty range overlaps the line containing `start`.
+pub(crate) fn lines_in(source: &str, start: usize, end: usize) -> Vec<SourceLine<'_>> {
+    let end = end.max(start + 1);
+    let mut line = line_at(source, start);
+    let mut lines = vec![];
+    loop {
+        lines.push(line);
+        if line.break_len == 0 {
+            break;
+        }
+        let next_start = line.start + line.len() + line.break_len;
+        if next_start >= end {
+            break;
+        }
+        let start_byte = line.start_byte + line.text.len() + line.break_len;
+        let rest = &source[start_byte..];
+        let len = rest.find(['\n', '\r']).unwrap_or(rest.len());
+        line = SourceLine {
+            number: line.number + 1,
+            start: next_start,
+            start_byte,
+            text: &rest[..len],
+            break_len: break_len(&rest[len..]),
+        };
+    }
+    lines
+}
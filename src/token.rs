@@ -0,0 +1,25 @@
+use std::fmt::Debug;
+
+use crate::{Diagnostic, Rlex, Spanned};
+
+/// A token type that knows how to lex itself, usually implemented with
+/// `#[derive(RlexToken)]` from the `derive` feature.
+///
+/// The source lifetime `'a` lets tokens hold `&'a str` slices of the source.
+pub trait RlexToken<'a>: Sized + Debug {
+    /// Lexes the token at the cursor and moves past it. Returns `None` without moving
+    /// if nothing matches, or after moving past text that is skipped.
+    ///
+    /// This is a [`Lexer`](crate::Lexer), so it can be passed to [`Rlex::lex`] to lex
+    /// with your own state type.
+    fn lex_token<S: Debug>(r: &mut Rlex<'a, S, Self>) -> Option<Self>;
+
+    /// Lexes all of `source`, returning the spanned tokens and an error for each run of
+    /// text that no token matched.
+    fn lex_all(source: &'a str) -> (Vec<Spanned<Self>>, Vec<Diagnostic>) {
+        let mut r: Rlex<'a, (), Self> = Rlex::new(source, ());
+        let tokens = r.lex(Self::lex_token).collect();
+        let (_, errors) = r.token_consume_with_errors();
+        (tokens, errors)
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::snippet::LineIndex;
+
+/// A single call recorded by the trace system.
+///
+/// Events are recorded when a call returns, so calls made from inside another traced
+/// call appear before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// The event's sequence number in the trace.
+    pub index: usize,
+    /// The name of the method that was called, such as `"next_by"`.
+    pub method: &'static str,
+    /// The category of the method that was called.
+    pub category: TraceCategory,
+    /// The arguments the method was called with, rendered as text.
+    pub args: Vec<String>,
+    /// The value the method returned, rendered as text, if it returns one worth showing.
+    pub result: Option<String>,
+    /// The cursor position when the call started.
+    pub pos_before: usize,
+    /// The cursor position when the call returned.
+    pub pos_after: usize,
+    /// The lexer state when the call returned, rendered with `Debug`.
+    pub state: String,
+    /// How many traced calls were in progress when this one was made. Calls made
+    /// directly by the user have depth 0.
+    pub depth: usize,
+    /// The marked position when the call returned.
+    pub mark: usize,
+    /// The last token on the token stack when the call returned, rendered with `Debug`.
+    pub last_token: Option<String>,
+    /// When the call started, measured from when tracing was turned on.
+    pub time: Duration,
+    /// How long the call took, including any traced calls it made.
+    pub duration: Duration,
+}
+
+impl TraceEvent {
+    /// Renders the event against the source it was recorded from: the source line with
+    /// a caret under the cursor and the mark, followed by the state and last token.
+    pub fn render(&self, source: &str) -> String {
+        self.render_in(&LineIndex::new(source))
+    }
+
+    /// Renders the event against an index of the source's lines, so rendering many
+    /// events scans the source only once.
+    pub(crate) fn render_in(&self, lines: &LineIndex<'_>) -> String {
+        let line = lines.line_at(self.pos_after);
+        let gutter = line.number.to_string().len();
+        let mut out = format!("{}\n", self);
+        out += &format!("{} | {}\n", line.number, line.display());
+        let col = self.pos_after - line.start;
+        out += &format!("{:gutter$} | {:col$}^ cursor\n", "", "");
+        let mark_line = lines.line_at(self.mark);
+        if mark_line.number == line.number {
+            let col = self.mark - line.start;
+            out += &format!("{:gutter$} | {:col$}^ mark\n", "", "");
+        } else {
+            let col = self.mark - mark_line.start + 1;
+            out += &format!("{:gutter$} = mark: {}:{}\n", "", mark_line.number, col);
+        }
+        out += &format!("{:gutter$} = state: {}\n", "", self.state);
+        match &self.last_token {
+            Some(token) => out += &format!("{:gutter$} = last token: {}\n", "", token),
+            None => out += &format!("{:gutter$} = last token: none\n", ""),
+        }
+        out
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    /// Renders the event as `index:method(args) -> result`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}({})",
+            self.index,
+            self.method,
+            self.args.join(", ")
+        )?;
+        if let Some(result) = &self.result {
+            write!(f, " -> {}", result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Steps forward and backward through a recorded trace, rendering each event against
+/// the source it was recorded from.
+#[derive(Debug, Clone)]
+pub struct TraceReplay<'a> {
+    lines: LineIndex<'a>,
+    events: Vec<TraceEvent>,
+    current: usize,
+}
+
+impl<'a> TraceReplay<'a> {
+    /// Creates a replay of `events` over `source`, starting at the first event.
+    pub fn new(source: &'a str, events: Vec<TraceEvent>) -> TraceReplay<'a> {
+        TraceReplay {
+            lines: LineIndex::new(source),
+            events,
+            current: 0,
+        }
+    }
+
+    /// Returns the number of events in the replay.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns `true` if the replay has no events.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns the index of the current event within the replay.
+    pub fn index(&self) -> usize {
+        self.current
+    }
+
+    /// Returns the current event, or `None` if the replay is empty.
+    pub fn current(&self) -> Option<&TraceEvent> {
+        self.events.get(self.current)
+    }
+
+    /// Moves to the next event and returns it, or returns `None` and stays put at the
+    /// last event.
+    pub fn step_forward(&mut self) -> Option<&TraceEvent> {
+        if self.current + 1 >= self.events.len() {
+            return None;
+        }
+        self.current += 1;
+        self.current()
+    }
+
+    /// Moves to the previous event and returns it, or returns `None` and stays put at
+    /// the first event.
+    pub fn step_back(&mut self) -> Option<&TraceEvent> {
+        if self.current == 0 || self.events.is_empty() {
+            return None;
+        }
+        self.current -= 1;
+        self.current()
+    }
+
+    /// Moves to the event at `index` and returns it, or returns `None` and stays put if
+    /// there is no such event.
+    pub fn seek(&mut self, index: usize) -> Option<&TraceEvent> {
+        if index >= self.events.len() {
+            return None;
+        }
+        self.current = index;
+        self.current()
+    }
+
+    /// Renders the current event, or returns an empty string if the replay is empty.
+    pub fn render(&self) -> String {
+        match self.current() {
+            Some(event) => event.render_in(&self.lines),
+            None => "".to_owned(),
+        }
+    }
+}
+
+/// A group of related traced methods, used to filter the trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TraceCategory {
+    /// Methods that move the cursor or set marks, such as `next`, `goto_pos` and `eat`.
+    Navigation,
+    /// Methods that look around the cursor without moving it, such as `peek` and `next_is`.
+    Peeks,
+    /// Methods that report on the cursor or source, such as `char`, `at_end` and `line`.
+    Queries,
+    /// Methods that push, pop or read tokens, or record errors.
+    Tokens,
+    /// Methods that read or change the lexer state, configuration or checkpoints.
+    State,
+    /// Methods that work with the collection buffer.
+    Collection,
+}
+
+/// Decides which events reach the trace sink.
+///
+/// The default filter lets every event through. Filters combine, so an event must
+/// pass all of them to be recorded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    categories: Option<Vec<TraceCategory>>,
+    window: Option<Range<usize>>,
+    top_level_only: bool,
+}
+
+impl TraceFilter {
+    /// Creates a filter that lets every event through.
+    pub fn new() -> TraceFilter {
+        TraceFilter::default()
+    }
+
+    /// Only records methods in one of `categories`.
+    pub fn only(mut self, categories: &[TraceCategory]) -> TraceFilter {
+        self.categories = Some(categories.to_vec());
+        self
+    }
+
+    /// Only records calls where the cursor was inside `window` before or after the
+    /// call, or moved across it.
+    pub fn within(mut self, window: Range<usize>) -> TraceFilter {
+        self.window = Some(window);
+        self
+    }
+
+    /// Only records calls made directly by the user, dropping the calls a traced
+    /// method makes internally, so `next_until('x')` is recorded once rather than with
+    /// every `char()` and `next()` inside it.
+    pub fn top_level_only(mut self) -> TraceFilter {
+        self.top_level_only = true;
+        self
+    }
+
+    /// Returns true if `event` passes the filter.
+    pub fn allows(&self, event: &TraceEvent) -> bool {
+        self.admits(event.category, event.depth) && self.overlaps(event.pos_before, event.pos_after)
+    }
+
+    /// Returns true if a call in `category` made at `depth` passes the category and
+    /// nesting filters, which can be checked before the call runs.
+    pub(crate) fn admits(&self, category: TraceCategory, depth: usize) -> bool {
+        if self.top_level_only && depth > 0 {
+            return false;
+        }
+        match &self.categories {
+            Some(categories) => categories.contains(&category),
+            None => true,
+        }
+    }
+
+    /// Returns true if a call that moved the cursor from `pos_before` to `pos_after`
+    /// passes the window filter.
+    pub(crate) fn overlaps(&self, pos_before: usize, pos_after: usize) -> bool {
+        match &self.window {
+            Some(window) => {
+                let low = pos_before.min(pos_after);
+                let high = pos_before.max(pos_after);
+                low < window.end && high >= window.start
+            }
+            None => true,
+        }
+    }
+}
+
+/// Where and how deep a traced call started, handed from `trace_enter` to `trace_log`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TraceFrame {
+    pub(crate) position: usize,
+    pub(crate) depth: usize,
+    pub(crate) category: TraceCategory,
+    /// Whether the call passed the filters checked on entry and tracing was on.
+    pub(crate) record: bool,
+    pub(crate) started: Option<Instant>,
+}
+
+/// Receives trace events as they are recorded.
+///
+/// Sinks that keep events in memory expose them through [`TraceSink::events`], which is
+/// what [`Rlex::trace_events`](crate::Rlex::trace_events) and
+/// [`Rlex::trace_emit`](crate::Rlex::trace_emit) read from.
+pub trait TraceSink {
+    /// Receives a newly recorded event.
+    fn record(&mut self, event: TraceEvent);
+
+    /// Returns the events the sink keeps, oldest first. Streaming sinks keep none.
+    fn events(&self) -> Vec<&TraceEvent> {
+        vec![]
+    }
+
+    /// Drops every kept event.
+    fn clear(&mut self) {}
+
+    /// Drops kept events whose index is `index` or later, as on a rollback.
+    fn truncate(&mut self, _index: usize) {}
+}
+
+impl fmt::Debug for dyn TraceSink + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceSink")
+            .field("events", &self.events().len())
+            .finish()
+    }
+}
+
+/// Keeps every event in memory. This is the sink used by [`Rlex::trace_on`](crate::Rlex::trace_on).
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    events: Vec<TraceEvent>,
+}
+
+impl MemorySink {
+    /// Creates an empty sink.
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+}
+
+impl TraceSink for MemorySink {
+    fn record(&mut self, event: TraceEvent) {
+        self.events.push(event);
+    }
+
+    fn events(&self) -> Vec<&TraceEvent> {
+        self.events.iter().collect()
+    }
+
+    fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    fn truncate(&mut self, index: usize) {
+        self.events.retain(|event| event.index < index);
+    }
+}
+
+/// Keeps only the most recent events, dropping the oldest once full.
+#[derive(Debug)]
+pub struct RingSink {
+    events: VecDeque<TraceEvent>,
+    capacity: usize,
+}
+
+impl RingSink {
+    /// Creates a sink that keeps at most `capacity` events.
+    pub fn new(capacity: usize) -> RingSink {
+        RingSink {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+}
+
+impl TraceSink for RingSink {
+    fn record(&mut self, event: TraceEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    fn events(&self) -> Vec<&TraceEvent> {
+        self.events.iter().collect()
+    }
+
+    fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    fn truncate(&mut self, index: usize) {
+        self.events.retain(|event| event.index < index);
+    }
+}
+
+/// Streams each event as a line of text to a writer, keeping nothing in memory.
+///
+/// Write errors are ignored so tracing never interrupts lexing.
+#[derive(Debug)]
+pub struct WriterSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> WriterSink<W> {
+    /// Creates a sink that writes to `writer`.
+    pub fn new(writer: W) -> WriterSink<W> {
+        WriterSink { writer }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> TraceSink for WriterSink<W> {
+    fn record(&mut self, event: TraceEvent) {
+        let _ = writeln!(self.writer, "{}", event);
+    }
+}
+
+/// Hands each event to a callback, keeping nothing in memory.
+pub struct CallbackSink<F: FnMut(TraceEvent)> {
+    callback: F,
+}
+
+impl<F: FnMut(TraceEvent)> CallbackSink<F> {
+    /// Creates a sink that calls `callback` with each event.
+    pub fn new(callback: F) -> CallbackSink<F> {
+        CallbackSink { callback }
+    }
+}
+
+impl<F: FnMut(TraceEvent)> TraceSink for CallbackSink<F> {
+    fn record(&mut self, event: TraceEvent) {
+        (self.callback)(event);
+    }
+}
+
+/// Discards every event.
+#[derive(Debug, Default)]
+pub struct NoopSink;
+
+impl TraceSink for NoopSink {
+    fn record(&mut self, _event: TraceEvent) {}
+}